@@ -0,0 +1,246 @@
+//! The protocol hot paths: encoding segments in flush and decoding them in
+//! input, send plus flush, input of full windows, acks against a large
+//! snd_buf and transfers through a simulated lossy link.
+#[macro_use]
+extern crate criterion;
+extern crate kcp;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput};
+use kcp::{ByteBuffer, KCP};
+
+/// the payload of a full segment at the default MTU
+const MSS: usize = 1376;
+
+type Packets = Rc<RefCell<Vec<Vec<u8>>>>;
+
+/// a session whose output is collected, with windows of `wnd` segments
+fn session(wnd: isize) -> (KCP, Packets) {
+    let packets: Packets = Rc::new(RefCell::new(Vec::new()));
+    let out = packets.clone();
+    let mut kcp = KCP::new(1, move |buf: &mut ByteBuffer, size: usize| {
+        out.borrow_mut().push(buf.to_bytes()[..size].to_vec());
+    });
+    kcp.no_delay(1, 10, 2, 1);
+    kcp.wnd_size(wnd, wnd);
+    (kcp, packets)
+}
+
+fn send(kcp: &mut KCP, size: usize) -> isize {
+    let mut buf = ByteBuffer::with_capacity(size);
+    buf.write_bytes(&vec![7u8; size]).unwrap();
+    kcp.send(&mut buf)
+}
+
+fn input(kcp: &mut KCP, packets: &[Vec<u8>]) {
+    for packet in packets {
+        let mut buf = ByteBuffer::with_capacity(packet.len());
+        buf.write_bytes(packet).unwrap();
+        kcp.input(&mut buf);
+    }
+}
+
+/// a session that has heard from a peer with a receive window of `wnd`,
+/// so it may send that many segments at once
+fn opened(wnd: usize) -> (KCP, Packets) {
+    let (mut peer, hello) = session(wnd as isize);
+    send(&mut peer, 1);
+    peer.update(0);
+    let (mut kcp, packets) = session(wnd as isize);
+    input(&mut kcp, &hello.borrow());
+    kcp.update(0);
+    packets.borrow_mut().clear();
+    (kcp, packets)
+}
+
+/// a sender with `wnd` full segments queued
+fn queued(wnd: usize) -> (KCP, Packets) {
+    let (mut kcp, packets) = opened(wnd);
+    for _ in 0..wnd {
+        send(&mut kcp, MSS);
+    }
+    (kcp, packets)
+}
+
+/// a sender with `wnd` full segments in flight and the packets carrying
+/// them
+fn window(wnd: usize) -> (KCP, Vec<Vec<u8>>) {
+    let (mut kcp, packets) = queued(wnd);
+    kcp.flush();
+    let sent = packets.borrow_mut().drain(..).collect();
+    (kcp, sent)
+}
+
+fn codec(c: &mut Criterion) {
+    let wnd = 128;
+    let mut group = c.benchmark_group("codec");
+    group.throughput(Throughput::Bytes((wnd * MSS) as u64));
+    group.bench_function("encode", |b| {
+        b.iter_batched(|| queued(wnd).0, |mut kcp| kcp.flush(), BatchSize::SmallInput)
+    });
+    let (_, packets) = window(wnd);
+    group.bench_function("decode", |b| {
+        b.iter_batched(|| session(wnd as isize).0,
+                       |mut kcp| input(&mut kcp, &packets),
+                       BatchSize::SmallInput)
+    });
+    group.finish();
+}
+
+fn send_flush(c: &mut Criterion) {
+    let mut group = c.benchmark_group("send_flush");
+    for &size in &[16, 256, MSS, 16 * 1024, 128 * 1024] {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.iter_batched(|| opened(256).0,
+                           |mut kcp| {
+                               send(&mut kcp, size);
+                               kcp.flush();
+                               kcp
+                           },
+                           BatchSize::SmallInput)
+        });
+    }
+    group.finish();
+}
+
+fn input_burst(c: &mut Criterion) {
+    let mut group = c.benchmark_group("input_burst");
+    for &wnd in &[32, 256, 2048] {
+        let (_, packets) = window(wnd);
+        group.throughput(Throughput::Elements(wnd as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(wnd), &packets, |b, packets| {
+            b.iter_batched(|| session(wnd as isize).0,
+                           |mut kcp| {
+                               input(&mut kcp, packets);
+                               assert_eq!(kcp.peek_size(), MSS as isize);
+                               kcp
+                           },
+                           BatchSize::SmallInput)
+        });
+    }
+    group.finish();
+}
+
+fn acks(c: &mut Criterion) {
+    let wnd = 4096;
+    let (_, packets) = window(wnd);
+    let (mut receiver, replies) = session(wnd as isize);
+    receiver.update(0);
+    // one ack per packet, as sent when every packet is received alone. The
+    // first packet comes last, so una stays put and every ack has to find
+    // its segment among the others
+    let mut ordered = Vec::new();
+    for packet in &packets[1..] {
+        input(&mut receiver, std::slice::from_ref(packet));
+        receiver.flush();
+        ordered.extend(replies.borrow_mut().drain(..));
+    }
+    let mut reversed = ordered.clone();
+    reversed.reverse();
+    input(&mut receiver, &packets[..1]);
+    receiver.flush();
+    let last: Vec<Vec<u8>> = replies.borrow_mut().drain(..).collect();
+    ordered.extend(last.iter().cloned());
+    reversed.extend(last);
+
+    let mut group = c.benchmark_group("ack");
+    group.throughput(Throughput::Elements(wnd as u64));
+    for &(name, ref acks) in &[("in order", &ordered), ("reversed", &reversed)] {
+        group.bench_with_input(BenchmarkId::new(name, wnd), acks, |b, acks| {
+            b.iter_batched(|| window(wnd).0,
+                           |mut kcp| {
+                               input(&mut kcp, acks);
+                               assert_eq!(kcp.wait_snd(), 0);
+                               kcp
+                           },
+                           BatchSize::LargeInput)
+        });
+    }
+    group.finish();
+}
+
+/// a link delivering packets after `delay` millisec, losing one in `loss`
+struct Link {
+    queue: VecDeque<(u32, Vec<u8>)>,
+    delay: u32,
+    loss: u32,
+    seed: u32,
+}
+
+impl Link {
+    fn new(delay: u32, loss: u32) -> Self {
+        Link {
+            queue: VecDeque::new(),
+            delay,
+            loss,
+            seed: 1,
+        }
+    }
+
+    fn send(&mut self, current: u32, packets: &Packets) {
+        for packet in packets.borrow_mut().drain(..) {
+            self.seed = self.seed.wrapping_mul(1103515245).wrapping_add(12345);
+            if !(self.seed >> 16).is_multiple_of(self.loss) {
+                self.queue.push_back((current + self.delay, packet));
+            }
+        }
+    }
+
+    fn deliver(&mut self, current: u32, kcp: &mut KCP) {
+        while self.queue.front().is_some_and(|&(at, _)| at <= current) {
+            let (_, packet) = self.queue.pop_front().unwrap();
+            input(kcp, &[packet]);
+        }
+    }
+}
+
+/// move `bytes` in messages of one segment over a link with 20 millisec
+/// of delay each way and 1% loss
+fn transfer(wnd: isize, bytes: usize) {
+    let (mut sender, to_receiver) = session(wnd);
+    let (mut receiver, to_sender) = session(wnd);
+    let (mut forward, mut back) = (Link::new(20, 100), Link::new(20, 100));
+    let (mut sent, mut received) = (0, 0);
+    let mut buf = ByteBuffer::with_capacity(MSS);
+    let mut current = 0;
+    while received < bytes {
+        while sent < bytes && sender.wait_snd() < 2 * wnd {
+            send(&mut sender, MSS);
+            sent += MSS;
+        }
+        sender.update(current);
+        receiver.update(current);
+        forward.send(current, &to_receiver);
+        back.send(current, &to_sender);
+        forward.deliver(current, &mut receiver);
+        back.deliver(current, &mut sender);
+        loop {
+            buf.clear();
+            let n = receiver.recv(&mut buf);
+            if n < 0 {
+                break;
+            }
+            received += n as usize;
+        }
+        current += 1;
+    }
+}
+
+fn simulator(c: &mut Criterion) {
+    let bytes = 4 << 20;
+    let mut group = c.benchmark_group("simulator");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(bytes as u64));
+    for &wnd in &[128, 1024] {
+        group.bench_with_input(BenchmarkId::new("wnd", wnd), &wnd, |b, &wnd| {
+            b.iter(|| transfer(wnd, bytes))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, codec, send_flush, input_burst, acks, simulator);
+criterion_main!(benches);
@@ -0,0 +1,104 @@
+//! One 10ms tick over 100k sessions, driven by an UpdateScheduler and by
+//! updating every session. As on a busy server most sessions are idle, but
+//! 1% have unacked data in flight, waiting for their retransmission timers,
+//! and on every tick 100 more get a window probe, which they answer.
+#[macro_use]
+extern crate criterion;
+extern crate kcp;
+
+use criterion::Criterion;
+use kcp::{ByteBuffer, KCP, UpdateScheduler};
+
+const SESSIONS: usize = 100_000;
+/// one in this many sessions has data in flight from the start
+const IN_FLIGHT_EVERY: usize = 100;
+/// sessions receiving a packet on each tick
+const INPUTS_PER_TICK: usize = 100;
+/// the stride between the sessions receiving, coprime with SESSIONS so
+/// every session takes its turn
+const INPUT_STRIDE: usize = 997;
+/// CMD_WASK
+const WINDOW_PROBE: u8 = 83;
+
+fn send(kcp: &mut KCP) {
+    let mut buf = ByteBuffer::with_capacity(100);
+    buf.write_bytes(&[7; 100]).unwrap();
+    kcp.send(&mut buf);
+}
+
+/// sessions whose output goes nowhere, so what they send is never acked
+fn sessions() -> Vec<KCP> {
+    (0..SESSIONS)
+        .map(|conv| {
+            let mut kcp = KCP::new(conv as u32, |_: &mut ByteBuffer, _: usize| {});
+            kcp.no_delay(1, 10, 2, 1);
+            if conv % IN_FLIGHT_EVERY == 0 {
+                send(&mut kcp);
+            }
+            kcp.update(0);
+            kcp
+        })
+        .collect()
+}
+
+/// feed a window probe to the next INPUTS_PER_TICK sessions after `next`,
+/// returning them
+fn input_some(kcps: &mut [KCP], next: &mut usize, inputs: &mut Vec<usize>) {
+    inputs.clear();
+    for _ in 0..INPUTS_PER_TICK {
+        *next = (*next + INPUT_STRIDE) % SESSIONS;
+        let mut packet = [0; 24];
+        packet[..4].copy_from_slice(&(*next as u32).to_le_bytes());
+        packet[4] = WINDOW_PROBE;
+        packet[6] = 128;
+        kcps[*next].input_slice(&packet);
+        inputs.push(*next);
+    }
+}
+
+fn busy_sessions(c: &mut Criterion) {
+    let mut kcps = sessions();
+    let mut group = c.benchmark_group("100k sessions, 1% in flight");
+
+    let mut scheduler = UpdateScheduler::new();
+    for (slot, kcp) in kcps.iter().enumerate() {
+        if !kcp.is_idle() {
+            scheduler.schedule(slot, kcp.check(0));
+        }
+    }
+    let (mut due, mut inputs) = (Vec::new(), Vec::new());
+    let (mut current, mut next) = (0u32, 0);
+    group.bench_function("scheduler tick", |b| {
+        b.iter(|| {
+            current = current.wrapping_add(10);
+            input_some(&mut kcps, &mut next, &mut inputs);
+            for &slot in &inputs {
+                scheduler.schedule_now(slot);
+            }
+            due.clear();
+            scheduler.advance(current, &mut due);
+            for &slot in &due {
+                kcps[slot].update(current);
+                if !kcps[slot].is_idle() {
+                    scheduler.schedule(slot, kcps[slot].check(current));
+                }
+            }
+        })
+    });
+
+    let mut kcps = sessions();
+    let (mut current, mut next) = (0u32, 0);
+    group.bench_function("update all", |b| {
+        b.iter(|| {
+            current = current.wrapping_add(10);
+            input_some(&mut kcps, &mut next, &mut inputs);
+            for kcp in kcps.iter_mut() {
+                kcp.update(current);
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, busy_sessions);
+criterion_main!(benches);
@@ -0,0 +1,88 @@
+//! Bulk transfer between two sessions over an in-memory wire reordering
+//! the data packets and losing every 64th, at send and receive windows of
+//! 32 to 8192 segments.
+#[macro_use]
+extern crate criterion;
+extern crate kcp;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use criterion::{BenchmarkId, Criterion, Throughput};
+use kcp::{ByteBuffer, KCP};
+
+type Wire = Rc<RefCell<VecDeque<Vec<u8>>>>;
+
+/// one message per segment
+const MESSAGE: usize = 1024;
+
+fn endpoint(wnd: isize) -> (KCP, Wire) {
+    let wire: Wire = Rc::new(RefCell::new(VecDeque::new()));
+    let out = wire.clone();
+    let mut kcp = KCP::new(1, move |buf: &mut ByteBuffer, size: usize| {
+        out.borrow_mut().push_back(buf.to_bytes()[..size].to_vec());
+    });
+    kcp.no_delay(1, 10, 2, 1);
+    kcp.wnd_size(wnd, wnd);
+    (kcp, wire)
+}
+
+/// lossy: the packets arrive in reverse and every 64th is lost
+fn deliver(wire: &Wire, kcp: &mut KCP, lossy: bool) {
+    let mut packets: Vec<Vec<u8>> = wire.borrow_mut().drain(..).collect();
+    if lossy {
+        packets.reverse();
+    }
+    for (i, packet) in packets.into_iter().enumerate() {
+        if lossy && i % 64 == 63 {
+            continue;
+        }
+        let mut buf = ByteBuffer::with_capacity(packet.len());
+        buf.write_bytes(&packet).unwrap();
+        kcp.input(&mut buf);
+    }
+}
+
+/// move `messages` messages from one session to the other
+fn transfer(wnd: isize, messages: usize) {
+    let (mut sender, to_receiver) = endpoint(wnd);
+    let (mut receiver, to_sender) = endpoint(wnd);
+    let message = vec![7u8; MESSAGE];
+    let (mut sent, mut received) = (0, 0);
+    let mut buf = ByteBuffer::with_capacity(MESSAGE);
+    let mut current = 0;
+    while received < messages {
+        while sent < messages && sender.wait_snd() < 2 * wnd {
+            buf.clear();
+            buf.write_bytes(&message).unwrap();
+            sender.send(&mut buf);
+            sent += 1;
+        }
+        sender.update(current);
+        receiver.update(current);
+        deliver(&to_receiver, &mut receiver, true);
+        deliver(&to_sender, &mut sender, false);
+        while receiver.peek_size() > 0 {
+            buf.clear();
+            receiver.recv(&mut buf);
+            received += 1;
+        }
+        current += 1;
+    }
+}
+
+fn windows(c: &mut Criterion) {
+    let mut group = c.benchmark_group("transfer");
+    group.sample_size(10);
+    for &wnd in &[32, 128, 512, 2048, 8192] {
+        let messages = 16384;
+        group.throughput(Throughput::Bytes((messages * MESSAGE) as u64));
+        group.bench_with_input(BenchmarkId::new("wnd", wnd), &wnd, |b, &wnd| {
+            b.iter(|| transfer(wnd, messages))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, windows);
+criterion_main!(benches);
@@ -0,0 +1,7 @@
+//! Builds the `ikcp_*` functions of `kcp::capi` into `libkcp`, so C and C++
+//! programs written against `ikcp.c` can link against it. It lives in its own
+//! package because a `cdylib` target in the kcp crate itself would also be
+//! built for `no_std` dependents, which cannot link one.
+extern crate kcp_core;
+
+pub use kcp_core::capi::*;
@@ -0,0 +1,83 @@
+/*
+ * ikcp.h - C interface of the Rust KCP implementation.
+ *
+ * Declares the subset of skywind3000's ikcp.h exported by the kcp cdylib,
+ * with identical names and signatures so existing C/C++ callers only need
+ * to relink. test_header in test/test_capi.rs checks that it declares
+ * exactly the functions exported by src/capi.rs.
+ */
+#ifndef __IKCP_H__
+#define __IKCP_H__
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef uint32_t IUINT32;
+
+/* the control block is opaque, fields are not accessible from C */
+typedef struct IKCPCB ikcpcb;
+
+/* create a new kcp control object, 'conv' must equal in two endpoint
+ * from the same connection. 'user' will be passed to the output callback */
+ikcpcb* ikcp_create(IUINT32 conv, void *user);
+
+/* release kcp control object */
+void ikcp_release(ikcpcb *kcp);
+
+/* set output callback, which will be invoked by kcp */
+void ikcp_setoutput(ikcpcb *kcp, int (*output)(const char *buf, int len,
+	ikcpcb *kcp, void *user));
+
+/* user/upper level recv: returns size, returns below zero for EAGAIN.
+ * A negative len peeks at the next message of up to -len bytes, a NULL
+ * buffer drops it */
+int ikcp_recv(ikcpcb *kcp, char *buffer, int len);
+
+/* user/upper level send, returns below zero for error */
+int ikcp_send(ikcpcb *kcp, const char *buffer, int len);
+
+/* update state (call it repeatedly, every 10ms-100ms), or you can ask
+ * ikcp_check when to call it again (without ikcp_input/_send calling).
+ * 'current' - current timestamp in millisec. */
+void ikcp_update(ikcpcb *kcp, IUINT32 current);
+
+/* determine when should you invoke ikcp_update */
+IUINT32 ikcp_check(const ikcpcb *kcp, IUINT32 current);
+
+/* when you received a low level packet (eg. UDP packet), call it */
+int ikcp_input(ikcpcb *kcp, const char *data, long size);
+
+/* flush pending data */
+void ikcp_flush(ikcpcb *kcp);
+
+/* check the size of next message in the recv queue */
+int ikcp_peeksize(const ikcpcb *kcp);
+
+/* change MTU size, default is 1400 */
+int ikcp_setmtu(ikcpcb *kcp, int mtu);
+
+/* set maximum window size: sndwnd=32, rcvwnd=32 by default */
+int ikcp_wndsize(ikcpcb *kcp, int sndwnd, int rcvwnd);
+
+/* get how many packet is waiting to be sent */
+int ikcp_waitsnd(const ikcpcb *kcp);
+
+/* fastest: ikcp_nodelay(kcp, 1, 20, 2, 1)
+ * nodelay: 0:disable(default), 1:enable
+ * interval: internal update timer interval in millisec, default is 100ms
+ * resend: 0:disable fast resend(default), 1:enable fast resend
+ * nc: 0:normal congestion control(default), 1:disable congestion control */
+int ikcp_nodelay(ikcpcb *kcp, int nodelay, int interval, int resend, int nc);
+
+/* read conv from a raw packet */
+IUINT32 ikcp_getconv(const void *ptr);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,443 @@
+//! Batched UDP I/O for the tunnel.
+//!
+//! `SendBatch` collects the datagrams produced by `KCP::flush`, of one or
+//! many sessions, and hands them to the kernel together; `RecvBatch` reads
+//! as many waiting datagrams as it holds at once. On Linux this takes one
+//! `sendmmsg`/`recvmmsg` call per batch, elsewhere one call per datagram.
+//!
+//! On Linux the batches can also use UDP segmentation offload: with GSO a
+//! run of equal sized datagrams to one address, as flush produces from full
+//! segments, goes to the kernel as one buffer split by `UDP_SEGMENT`, and
+//! with GRO the kernel hands over coalesced datagrams that are split again
+//! by the size it reports. Both are off until enabled and fall back to
+//! plain datagrams where the kernel does not support them.
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+/// the most datagrams passed to one sendmmsg or recvmmsg call
+pub const BATCH_SIZE: usize = 32;
+/// the receive buffer size needed for GRO, the kernel coalesces up to it
+pub const GRO_BUF_SIZE: usize = 65535;
+
+/// datagrams waiting to be sent, each to its address or, if None, to the
+/// address the socket is connected to
+#[derive(Debug, Default)]
+pub struct SendBatch {
+    packets: Vec<(Vec<u8>, Option<SocketAddr>)>,
+    gso: bool,
+}
+
+impl SendBatch {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// send runs of equal sized datagrams with GSO if `udp` supports it,
+    /// returns whether it does
+    pub fn enable_gso(&mut self, udp: &UdpSocket) -> bool {
+        self.gso = sys::gso_supported(udp);
+        self.gso
+    }
+
+    /// GSO is in use, it is turned off again if a send with it fails
+    pub fn gso(&self) -> bool {
+        self.gso
+    }
+
+    pub fn push(&mut self, packet: Vec<u8>, addr: Option<SocketAddr>) {
+        self.packets.push((packet, addr));
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// send and clear every datagram, returns how many were sent. As with
+    /// any UDP datagram, the ones the socket refuses are lost
+    pub fn send(&mut self, udp: &UdpSocket) -> usize {
+        let sent = sys::send(udp, &self.packets, &mut self.gso);
+        self.packets.clear();
+        sent
+    }
+}
+
+/// buffers for up to `count` received datagrams
+#[derive(Debug)]
+pub struct RecvBatch {
+    bufs: Vec<Vec<u8>>,
+    /// the buffer, offset, length and source of each datagram received
+    received: Vec<(usize, usize, usize, SocketAddr)>,
+    gro: bool,
+}
+
+impl RecvBatch {
+    /// room for `count` datagrams of up to `size` bytes each
+    pub fn new(count: usize, size: usize) -> Self {
+        RecvBatch {
+            bufs: vec![vec![0; size]; count],
+            received: Vec::with_capacity(count),
+            gro: false,
+        }
+    }
+
+    /// accept coalesced datagrams from `udp` if it supports GRO, growing
+    /// the buffers to GRO_BUF_SIZE; returns whether it does
+    pub fn enable_gro(&mut self, udp: &UdpSocket) -> bool {
+        self.gro = sys::enable_gro(udp);
+        if self.gro {
+            for buf in &mut self.bufs {
+                if buf.len() < GRO_BUF_SIZE {
+                    buf.resize(GRO_BUF_SIZE, 0);
+                }
+            }
+        }
+        self.gro
+    }
+
+    /// wait for a datagram as long as the socket's read timeout, then take
+    /// every other one already waiting that fits, returns how many
+    pub fn recv(&mut self, udp: &UdpSocket) -> io::Result<usize> {
+        self.received.clear();
+        sys::recv(udp, &mut self.bufs, self.gro, &mut self.received)?;
+        Ok(self.received.len())
+    }
+
+    /// the datagrams of the last recv
+    pub fn len(&self) -> usize {
+        self.received.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.received.is_empty()
+    }
+
+    /// the `i`th datagram of the last recv and its source
+    pub fn get(&self, i: usize) -> (&[u8], SocketAddr) {
+        let (buf, offset, len, addr) = self.received[i];
+        (&self.bufs[buf][offset..offset + len], addr)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::cmp::min;
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+    use libc;
+    use super::BATCH_SIZE;
+
+    /// the most datagrams the kernel takes in one GSO buffer
+    const GSO_SEGMENTS: usize = 64;
+    /// the most bytes of one GSO buffer, below the 64 KiB UDP limit
+    const GSO_BYTES: usize = 65000;
+
+    /// room for one cmsg of an int, 8 byte aligned
+    type Control = [u64; 4];
+
+    fn to_sockaddr(addr: &SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+        match *addr {
+            SocketAddr::V4(ref addr) => {
+                let sin = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr = libc::in_addr { s_addr: u32::from_ne_bytes(addr.ip().octets()) };
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+            }
+            SocketAddr::V6(ref addr) => {
+                let sin6 = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_addr = libc::in6_addr { s6_addr: addr.ip().octets() };
+                sin6.sin6_scope_id = addr.scope_id();
+                mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+            }
+        }
+    }
+
+    fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+                Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                Some(SocketAddr::V6(SocketAddrV6::new(ip,
+                                                      u16::from_be(sin6.sin6_port),
+                                                      sin6.sin6_flowinfo,
+                                                      sin6.sin6_scope_id)))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn gso_supported(udp: &UdpSocket) -> bool {
+        let mut size: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        unsafe {
+            libc::getsockopt(udp.as_raw_fd(),
+                             libc::SOL_UDP,
+                             libc::UDP_SEGMENT,
+                             &mut size as *mut _ as *mut libc::c_void,
+                             &mut len) == 0
+        }
+    }
+
+    pub fn enable_gro(udp: &UdpSocket) -> bool {
+        let on: libc::c_int = 1;
+        unsafe {
+            libc::setsockopt(udp.as_raw_fd(),
+                             libc::SOL_UDP,
+                             libc::UDP_GRO,
+                             &on as *const _ as *const libc::c_void,
+                             mem::size_of::<libc::c_int>() as libc::socklen_t) == 0
+        }
+    }
+
+    /// split packets into the runs sent as one message: with GSO datagrams
+    /// of the size of the first to the same address, the last may be shorter
+    fn group(packets: &[(Vec<u8>, Option<SocketAddr>)], gso: bool) -> Vec<(usize, usize)> {
+        let mut groups = Vec::new();
+        let mut start = 0;
+        while start < packets.len() {
+            let (size, addr) = (packets[start].0.len(), packets[start].1);
+            let mut end = start + 1;
+            let mut bytes = size;
+            while gso && size > 0 && end < packets.len() && end - start < GSO_SEGMENTS {
+                let len = packets[end].0.len();
+                if packets[end].1 != addr || len > size || len == 0 || bytes + len > GSO_BYTES {
+                    break;
+                }
+                bytes += len;
+                end += 1;
+                if len < size {
+                    break;
+                }
+            }
+            groups.push((start, end));
+            start = end;
+        }
+        groups
+    }
+
+    fn send_one(udp: &UdpSocket, packet: &[u8], addr: Option<SocketAddr>) -> bool {
+        let result = match addr {
+            Some(addr) => udp.send_to(packet, addr),
+            None => udp.send(packet),
+        };
+        result.is_ok()
+    }
+
+    pub fn send(udp: &UdpSocket,
+                packets: &[(Vec<u8>, Option<SocketAddr>)],
+                gso: &mut bool)
+                -> usize {
+        let mut sent = 0;
+        let groups = group(packets, *gso);
+        for chunk in groups.chunks(BATCH_SIZE) {
+            let mut iovs: Vec<libc::iovec> = Vec::new();
+            for &(start, end) in chunk {
+                for (packet, _) in &packets[start..end] {
+                    iovs.push(libc::iovec {
+                        iov_base: packet.as_ptr() as *mut libc::c_void,
+                        iov_len: packet.len(),
+                    });
+                }
+            }
+            let mut names: Vec<libc::sockaddr_storage> =
+                vec![unsafe { mem::zeroed() }; chunk.len()];
+            let mut controls: Vec<Control> = vec![[0; 4]; chunk.len()];
+            let mut msgs: Vec<libc::mmsghdr> = Vec::with_capacity(chunk.len());
+            let mut iov = 0;
+            for (i, &(start, end)) in chunk.iter().enumerate() {
+                let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                if let Some(ref addr) = packets[start].1 {
+                    msg.msg_hdr.msg_namelen = to_sockaddr(addr, &mut names[i]);
+                    msg.msg_hdr.msg_name = &mut names[i] as *mut _ as *mut libc::c_void;
+                }
+                msg.msg_hdr.msg_iov = &mut iovs[iov];
+                msg.msg_hdr.msg_iovlen = (end - start) as _;
+                iov += end - start;
+                if end - start > 1 {
+                    let size = packets[start].0.len() as u16;
+                    let hdr = &mut msg.msg_hdr;
+                    hdr.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+                    hdr.msg_controllen = unsafe { libc::CMSG_SPACE(2) } as _;
+                    unsafe {
+                        let cmsg = libc::CMSG_FIRSTHDR(hdr);
+                        (*cmsg).cmsg_level = libc::SOL_UDP;
+                        (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                        (*cmsg).cmsg_len = libc::CMSG_LEN(2) as _;
+                        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, size);
+                    }
+                }
+                msgs.push(msg);
+            }
+            let mut done = 0;
+            while done < msgs.len() {
+                let n = unsafe {
+                    libc::sendmmsg(udp.as_raw_fd(),
+                                   msgs[done..].as_mut_ptr(),
+                                   (msgs.len() - done) as libc::c_uint,
+                                   0)
+                };
+                if n > 0 {
+                    for &(start, end) in &chunk[done..done + n as usize] {
+                        sent += end - start;
+                    }
+                    done += n as usize;
+                    continue;
+                }
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                let (start, end) = chunk[done];
+                if end - start > 1 {
+                    // the kernel or device cannot segment, stop using GSO
+                    *gso = false;
+                    for &(ref packet, addr) in &packets[start..end] {
+                        if send_one(udp, packet, addr) {
+                            sent += 1;
+                        }
+                    }
+                }
+                // otherwise drop the datagram the kernel refused and go on
+                done += 1;
+            }
+        }
+        sent
+    }
+
+    /// the size of the datagrams GRO coalesced into one message
+    fn gro_size(hdr: &libc::msghdr) -> Option<usize> {
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(hdr) };
+        while !cmsg.is_null() {
+            let (level, kind) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+            if level == libc::SOL_UDP && kind == libc::UDP_GRO {
+                let data = unsafe { libc::CMSG_DATA(cmsg) } as *const libc::c_int;
+                return Some(unsafe { ptr::read_unaligned(data) } as usize);
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(hdr, cmsg) };
+        }
+        None
+    }
+
+    pub fn recv(udp: &UdpSocket,
+                bufs: &mut [Vec<u8>],
+                gro: bool,
+                received: &mut Vec<(usize, usize, usize, SocketAddr)>)
+                -> io::Result<()> {
+        let count = bufs.len();
+        let mut names: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; count];
+        let mut controls: Vec<Control> = vec![[0; 4]; count];
+        let mut iovs: Vec<libc::iovec> = bufs.iter_mut()
+            .map(|buf| {
+                libc::iovec {
+                    iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                    iov_len: buf.len(),
+                }
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = Vec::with_capacity(count);
+        for i in 0..count {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = &mut names[i] as *mut _ as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = &mut iovs[i];
+            msg.msg_hdr.msg_iovlen = 1;
+            if gro {
+                msg.msg_hdr.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+                msg.msg_hdr.msg_controllen = mem::size_of::<Control>() as _;
+            }
+            msgs.push(msg);
+        }
+        let n = unsafe {
+            libc::recvmmsg(udp.as_raw_fd(),
+                           msgs.as_mut_ptr(),
+                           count as libc::c_uint,
+                           libc::MSG_WAITFORONE,
+                           ptr::null_mut())
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for i in 0..n as usize {
+            // a truncated datagram could not be a valid packet
+            if msgs[i].msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+                continue;
+            }
+            let addr = match from_sockaddr(&names[i]) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            let len = msgs[i].msg_len as usize;
+            let size = match if gro { gro_size(&msgs[i].msg_hdr) } else { None } {
+                Some(size) if size > 0 => size,
+                _ => len,
+            };
+            if len == 0 {
+                received.push((i, 0, 0, addr));
+            }
+            let mut offset = 0;
+            while offset < len {
+                let end = min(offset + size, len);
+                received.push((i, offset, end - offset, addr));
+                offset = end;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io;
+    use std::net::{SocketAddr, UdpSocket};
+
+    pub fn gso_supported(_: &UdpSocket) -> bool {
+        false
+    }
+
+    pub fn enable_gro(_: &UdpSocket) -> bool {
+        false
+    }
+
+    pub fn send(udp: &UdpSocket,
+                packets: &[(Vec<u8>, Option<SocketAddr>)],
+                _: &mut bool)
+                -> usize {
+        let mut sent = 0;
+        for &(ref packet, addr) in packets {
+            let result = match addr {
+                Some(addr) => udp.send_to(packet, addr),
+                None => udp.send(packet),
+            };
+            if result.is_ok() {
+                sent += 1;
+            }
+        }
+        sent
+    }
+
+    pub fn recv(udp: &UdpSocket,
+                bufs: &mut [Vec<u8>],
+                _: bool,
+                received: &mut Vec<(usize, usize, usize, SocketAddr)>)
+                -> io::Result<()> {
+        if let Some(buf) = bufs.first_mut() {
+            let (len, addr) = udp.recv_from(buf)?;
+            received.push((0, 0, len, addr));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+extern crate kcp;
+
+use std::env;
+use std::process;
+use kcp::tunnel::{Client, Options, USAGE_KCP};
+
+fn usage(err: &str) -> ! {
+    eprintln!("{}", err);
+    eprintln!("usage: kcp-tun-client -l <tcp listen addr> -r <server udp addr> [options]");
+    eprintln!("{}", USAGE_KCP);
+    process::exit(2);
+}
+
+fn main() {
+    let opts = match Options::parse(env::args().skip(1)) {
+        Ok(ref opts) if opts.remote.is_empty() => {
+            usage("the remote address is required");
+        }
+        Ok(opts) => opts,
+        Err(e) => usage(&e),
+    };
+    let client = Client::bind(&opts).unwrap_or_else(|e| {
+        eprintln!("failed to listen on {}: {}", opts.listen, e);
+        process::exit(1);
+    });
+    if opts.socks5 {
+        println!("SOCKS5 proxy on {}, tunnel to {}", opts.listen, opts.remote);
+    } else {
+        println!("listening on {}, tunnel to {}", opts.listen, opts.remote);
+    }
+    if let Err(e) = client.run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,33 @@
+extern crate kcp;
+
+use std::env;
+use std::process;
+use kcp::tunnel::{Options, Server, USAGE_KCP};
+
+fn usage(err: &str) -> ! {
+    eprintln!("{}", err);
+    eprintln!("usage: kcp-tun-server -l <udp listen addr> -t <tcp target addr> [options]");
+    eprintln!("       kcp-tun-server -l <udp listen addr> --socks5 [options]");
+    eprintln!("{}", USAGE_KCP);
+    process::exit(2);
+}
+
+fn main() {
+    let opts = match Options::parse(env::args().skip(1)) {
+        Ok(opts) => opts,
+        Err(e) => usage(&e),
+    };
+    let server = Server::bind(&opts).unwrap_or_else(|e| {
+        eprintln!("failed to listen on {}: {}", opts.listen, e);
+        process::exit(1);
+    });
+    if opts.remote.is_empty() {
+        println!("listening on {}, forward to requested targets", opts.listen);
+    } else {
+        println!("listening on {}, forward to {}", opts.listen, opts.remote);
+    }
+    if let Err(e) = server.run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,103 @@
+//! Stand-in for `fixbuf::ByteBuffer` without the `std` feature, with the
+//! same methods and little endian integers so `kcp.rs` builds unchanged.
+use alloc::vec::Vec;
+
+/// a read or write went past the end of the buffer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Overflow;
+
+/// a fixed capacity buffer with separate read and write positions
+#[derive(Clone, Debug, Default)]
+pub struct ByteBuffer {
+    data: Vec<u8>,
+    capacity: usize,
+    rpos: usize,
+}
+
+impl ByteBuffer {
+    pub fn with_capacity(capacity: usize) -> Self {
+        ByteBuffer {
+            data: Vec::with_capacity(capacity),
+            capacity,
+            rpos: 0,
+        }
+    }
+
+    /// the capacity of the buffer
+    pub fn len(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.capacity == 0
+    }
+
+    /// the bytes written but not read yet
+    pub fn read_remain(&self) -> usize {
+        self.data.len() - self.rpos
+    }
+
+    pub fn get_wpos(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.rpos = 0;
+    }
+
+    /// everything written so far
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Overflow> {
+        if self.data.len() + bytes.len() > self.capacity {
+            return Err(Overflow);
+        }
+        self.data.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    pub fn write_u8(&mut self, value: u8) -> Result<(), Overflow> {
+        self.write_bytes(&[value])
+    }
+
+    pub fn write_u16(&mut self, value: u16) -> Result<(), Overflow> {
+        self.write_bytes(&[value as u8, (value >> 8) as u8])
+    }
+
+    pub fn write_u32(&mut self, value: u32) -> Result<(), Overflow> {
+        self.write_bytes(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8])
+    }
+
+    pub fn read_bytes(&mut self, n: usize) -> Result<Vec<u8>, Overflow> {
+        if self.read_remain() < n {
+            return Err(Overflow);
+        }
+        let bytes = self.data[self.rpos..self.rpos + n].to_vec();
+        self.rpos += n;
+        Ok(bytes)
+    }
+
+    pub fn read_u8_as_u32(&mut self) -> Result<u32, Overflow> {
+        self.read_le(1)
+    }
+
+    pub fn read_u16_as_u32(&mut self) -> Result<u32, Overflow> {
+        self.read_le(2)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, Overflow> {
+        self.read_le(4)
+    }
+
+    fn read_le(&mut self, n: usize) -> Result<u32, Overflow> {
+        if self.read_remain() < n {
+            return Err(Overflow);
+        }
+        let bytes = &self.data[self.rpos..self.rpos + n];
+        self.rpos += n;
+        Ok(bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u32))
+    }
+}
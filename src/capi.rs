@@ -0,0 +1,240 @@
+//! C ABI compatible with skywind3000's `ikcp.h`, so C and C++ programs can
+//! switch to this implementation by relinking against the `libkcp` built by
+//! the `capi` package.
+//!
+//! The declarations live in `include/ikcp.h`. As in `ikcp.c`, the output
+//! callback must not call back into the same `ikcpcb` while it is running.
+//!
+//! # Safety
+//!
+//! `kcp` arguments must be NULL or a pointer from `ikcp_create` that has not
+//! been released, and data pointers must be NULL or valid for `len` bytes.
+//! NULL control blocks are ignored, and functions returning `int` return -1
+//! for them.
+#![allow(clippy::missing_safety_doc)]
+use std::cell::Cell;
+use std::os::raw::{c_char, c_int, c_long, c_void};
+use std::ptr;
+use std::rc::Rc;
+use std::slice;
+use fixbuf::ByteBuffer;
+use kcp::{get_conv, KCP};
+
+/// int (*output)(const char *buf, int len, ikcpcb *kcp, void *user)
+pub type OutputFn = extern "C" fn(*const c_char, c_int, *mut IKCPCB, *mut c_void) -> c_int;
+
+#[derive(Clone, Copy)]
+struct Output {
+    output: Option<OutputFn>,
+    kcp: *mut IKCPCB,
+    user: *mut c_void,
+}
+
+/// opaque control block handed out to C callers as `ikcpcb *`
+pub struct IKCPCB {
+    kcp: KCP,
+    output: Rc<Cell<Output>>,
+}
+
+/// a C buffer of `len` bytes as a slice, None for NULL with a non-zero len
+unsafe fn as_slice<'a>(data: *const c_char, len: usize) -> Option<&'a [u8]> {
+    if len == 0 {
+        Some(&[])
+    } else if data.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts(data as *const u8, len))
+    }
+}
+
+/// create a new kcp control object, 'conv' must equal in two endpoint
+/// from the same connection. 'user' will be passed to the output callback
+#[no_mangle]
+pub extern "C" fn ikcp_create(conv: u32, user: *mut c_void) -> *mut IKCPCB {
+    let output = Rc::new(Cell::new(Output {
+        output: None,
+        kcp: ptr::null_mut(),
+        user,
+    }));
+    let state = output.clone();
+    let kcp = KCP::new(conv, move |buf: &mut ByteBuffer, size: usize| {
+        let out = state.get();
+        if let Some(f) = out.output {
+            let bytes = buf.to_bytes();
+            f(bytes.as_ptr() as *const c_char, size as c_int, out.kcp, out.user);
+        }
+    });
+    let handle = Box::into_raw(Box::new(IKCPCB { kcp, output }));
+    let cb = unsafe { &*handle };
+    let mut out = cb.output.get();
+    out.kcp = handle;
+    cb.output.set(out);
+    handle
+}
+
+/// release kcp control object
+#[no_mangle]
+pub unsafe extern "C" fn ikcp_release(kcp: *mut IKCPCB) {
+    if !kcp.is_null() {
+        drop(Box::from_raw(kcp));
+    }
+}
+
+/// set output callback, which will be invoked by kcp
+#[no_mangle]
+pub unsafe extern "C" fn ikcp_setoutput(kcp: *mut IKCPCB, output: Option<OutputFn>) {
+    if let Some(cb) = kcp.as_mut() {
+        let mut out = cb.output.get();
+        out.output = output;
+        cb.output.set(out);
+    }
+}
+
+/// user/upper level recv: returns size, returns below zero for EAGAIN.
+/// A negative len peeks at the next message of up to -len bytes, a NULL
+/// buffer drops it
+#[no_mangle]
+pub unsafe extern "C" fn ikcp_recv(kcp: *mut IKCPCB, buffer: *mut c_char, len: c_int) -> c_int {
+    let cb = match kcp.as_mut() {
+        Some(cb) => cb,
+        None => return -1,
+    };
+    let size = cb.kcp.peek_size();
+    if size < 0 {
+        // -1 for an empty queue, -2 for a partial message; an empty buffer
+        // is enough to tell them apart
+        return cb.kcp.peek(&mut ByteBuffer::with_capacity(0)) as c_int;
+    }
+    if size as usize > len.unsigned_abs() as usize {
+        return -3;
+    }
+    let data = if len < 0 {
+        cb.kcp.peek_bytes()
+    } else {
+        cb.kcp.recv_bytes()
+    };
+    let data = data.unwrap();
+    if !buffer.is_null() {
+        ptr::copy_nonoverlapping(data.as_ptr(), buffer as *mut u8, data.len());
+    }
+    data.len() as c_int
+}
+
+/// user/upper level send, returns below zero for error
+#[no_mangle]
+pub unsafe extern "C" fn ikcp_send(kcp: *mut IKCPCB, buffer: *const c_char, len: c_int) -> c_int {
+    let cb = match kcp.as_mut() {
+        Some(cb) => cb,
+        None => return -1,
+    };
+    if len < 0 {
+        return -1;
+    }
+    let data = match as_slice(buffer, len as usize) {
+        Some(data) => data,
+        None => return -1,
+    };
+    let mut buf = ByteBuffer::with_capacity(data.len());
+    buf.write_bytes(data).unwrap();
+    cb.kcp.send(&mut buf) as c_int
+}
+
+/// update state (call it repeatedly, every 10ms-100ms)
+#[no_mangle]
+pub unsafe extern "C" fn ikcp_update(kcp: *mut IKCPCB, current: u32) {
+    if let Some(cb) = kcp.as_mut() {
+        cb.kcp.update(current);
+    }
+}
+
+/// determine when should you invoke ikcp_update
+#[no_mangle]
+pub unsafe extern "C" fn ikcp_check(kcp: *const IKCPCB, current: u32) -> u32 {
+    match kcp.as_ref() {
+        Some(cb) => cb.kcp.check(current),
+        None => current,
+    }
+}
+
+/// when you received a low level packet (eg. UDP packet), call it
+#[no_mangle]
+pub unsafe extern "C" fn ikcp_input(kcp: *mut IKCPCB, data: *const c_char, size: c_long) -> c_int {
+    let cb = match kcp.as_mut() {
+        Some(cb) => cb,
+        None => return -1,
+    };
+    if size < 0 || data.is_null() {
+        return -1;
+    }
+    let data = slice::from_raw_parts(data as *const u8, size as usize);
+    cb.kcp.input_slice(data) as c_int
+}
+
+/// flush pending data
+#[no_mangle]
+pub unsafe extern "C" fn ikcp_flush(kcp: *mut IKCPCB) {
+    if let Some(cb) = kcp.as_mut() {
+        cb.kcp.flush();
+    }
+}
+
+/// check the size of next message in the recv queue
+#[no_mangle]
+pub unsafe extern "C" fn ikcp_peeksize(kcp: *const IKCPCB) -> c_int {
+    match kcp.as_ref() {
+        Some(cb) => cb.kcp.peek_size() as c_int,
+        None => -1,
+    }
+}
+
+/// change MTU size, default is 1400
+#[no_mangle]
+pub unsafe extern "C" fn ikcp_setmtu(kcp: *mut IKCPCB, mtu: c_int) -> c_int {
+    match kcp.as_mut() {
+        Some(cb) => cb.kcp.set_mtu(mtu as isize) as c_int,
+        None => -1,
+    }
+}
+
+/// set maximum window size: sndwnd=32, rcvwnd=32 by default
+#[no_mangle]
+pub unsafe extern "C" fn ikcp_wndsize(kcp: *mut IKCPCB, sndwnd: c_int, rcvwnd: c_int) -> c_int {
+    match kcp.as_mut() {
+        Some(cb) => cb.kcp.wnd_size(sndwnd as isize, rcvwnd as isize) as c_int,
+        None => -1,
+    }
+}
+
+/// get how many packet is waiting to be sent
+#[no_mangle]
+pub unsafe extern "C" fn ikcp_waitsnd(kcp: *const IKCPCB) -> c_int {
+    match kcp.as_ref() {
+        Some(cb) => cb.kcp.wait_snd() as c_int,
+        None => -1,
+    }
+}
+
+/// fastest: ikcp_nodelay(kcp, 1, 20, 2, 1)
+#[no_mangle]
+pub unsafe extern "C" fn ikcp_nodelay(kcp: *mut IKCPCB,
+                                      nodelay: c_int,
+                                      interval: c_int,
+                                      resend: c_int,
+                                      nc: c_int)
+                                      -> c_int {
+    match kcp.as_mut() {
+        Some(cb) => {
+            cb.kcp.no_delay(nodelay as isize,
+                            interval as isize,
+                            resend as isize,
+                            nc as isize) as c_int
+        }
+        None => -1,
+    }
+}
+
+/// read conv from a raw packet, 0 for NULL
+#[no_mangle]
+pub unsafe extern "C" fn ikcp_getconv(ptr: *const c_void) -> u32 {
+    as_slice(ptr as *const c_char, 4).and_then(get_conv).unwrap_or(0)
+}
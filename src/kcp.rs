@@ -1,9 +1,17 @@
+use limiter::RateLimiter;
+use pool::BufferPool;
+use ring::SegmentRing;
 use segment::Segment;
-use std::collections::VecDeque;
-use fixbuf::ByteBuffer;
-use std::{i32, u32};
-use std::cmp::{min, max};
-use std::default::Default;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use bytes::{Bytes, BytesMut};
+use ByteBuffer;
+use core::cmp::{min, max};
+use core::default::Default;
+use core::mem;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 /// all time value is milliseconds
 /// retransmission timeout with no delay but at least 30 ms
 const RTO_NDL: u32 = 30;
@@ -39,6 +47,12 @@ const THRESH_MIN: u32 = 2;
 /// the time to wait the probe window size
 const PROBE_INIT: u32 = 7000;
 const PROBE_LIMIT: u32 = 120000;
+/// how many times a MTU probe is sent before its size is given up
+const PMTU_PROBES: u32 = 3;
+/// the time to wait before searching for a larger MTU again
+const PMTU_RAISE: u32 = 600000;
+/// the bytes in front of the data of a CMD_PIECE
+const PIECE_OVERHEAD: u32 = 9;
 
 
 /// Push send the packet
@@ -49,6 +63,180 @@ const CMD_ACK: u32 = 82;
 const CMD_WASK: u32 = 83;
 /// Wins tell the other side the size of window
 const CMD_WINS: u32 = 84;
+/// Sack acks una plus a bitmap of the segments received after it
+const CMD_SACK: u32 = 85;
+/// PushCont a fragment of a message too large for 255 fragments, the
+/// message goes on after a PushCont with frg 0
+const CMD_PUSH_CONT: u32 = 86;
+/// MtuProbe a padded segment of sn bytes in total, sent alone in a packet
+const CMD_MTU_PROBE: u32 = 87;
+/// MtuAck the probe of sn bytes got through
+const CMD_MTU_ACK: u32 = 88;
+/// Skip the data of sn expired, frg is 0 if it ended a message and 1 if
+/// the rest of the message has to be dropped too
+const CMD_SKIP: u32 = 89;
+/// Unreliable a datagram outside of sn, never acked nor retransmitted
+const CMD_UNRELIABLE: u32 = 90;
+/// PushHead the first fragment of a message, sent in unordered mode
+const CMD_PUSH_HEAD: u32 = 91;
+/// Piece a part of the segment sn, sent when the MTU fell below its size.
+/// The payload starts with the cmd of the segment, the offset of the part
+/// and the length of the whole segment
+const CMD_PIECE: u32 = 92;
+/// marks in rcv_buf a segment already delivered in unordered mode
+const CMD_DELIVERED: u32 = 0;
+
+/// returned by send while the queued data is above the high watermark
+pub const ERR_WOULD_BLOCK: isize = -4;
+
+/// the format of KcpSnapshot written by this version, restore refuses
+/// snapshots of any other
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+/// set in frg of non-push segments by peers that understand CMD_SACK
+const FRG_SACK: u32 = 1;
+/// how many flushes advertise SACK support before giving up on the peer
+const SACK_ADVERTISE: u32 = 16;
+
+/// when received segments are acknowledged
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum AckPolicy {
+    /// acks wait for the next flush, every `interval` millisec (default)
+    #[default]
+    Interval,
+    /// acks are sent at the end of every input call
+    NoDelay,
+    /// acks are sent once `every` segments are waiting for one, or the
+    /// oldest has waited `max_delay` millisec, whichever comes first
+    Delayed { max_delay: u32, every: u32 },
+}
+
+/// how data segments are spread out over time by flush
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Pacing {
+    /// every eligible segment is sent at once (default)
+    #[default]
+    Off,
+    /// send a congestion window per smoothed rtt, plus a quarter
+    Window,
+    /// send at most this many bytes per second
+    Rate(u32),
+}
+
+/// the allocations of a session, see `KCP::alloc_stats`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AllocStats {
+    /// payload buffers allocated for sent messages and received segments
+    pub payloads: u64,
+    /// payload buffers taken from the buffer pool instead
+    pub reused: u64,
+    /// output and scratch buffers allocated, one output buffer by new
+    pub buffers: u64,
+    /// copies read out of a ByteBuffer by send with a pool and by input,
+    /// send_bytes and input_slice avoid them
+    pub reads: u64,
+    /// times the queues, rings and acklist holding segments grew; their
+    /// room is kept, so it stops once the windows were full once
+    pub segments: u64,
+}
+
+/// the protocol state of a KCP session, taken by `KCP::snapshot` and
+/// resumed by `KCP::restore`, e.g. to hand a session over to a restarted
+/// process. The output and writable callbacks, a shared rate limiter and
+/// a buffer pool are not part of it, and an own rate limiter restarts with
+/// a full bucket
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct KcpSnapshot {
+    version: u32,
+    conv: u32,
+    mtu: u32,
+    mss: u32,
+    state: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    rcv_nxt: u32,
+    ssthresh: u32,
+    rx_rttval: u32,
+    rx_srtt: u32,
+    rx_rto: u32,
+    rx_minrto: u32,
+    snd_wnd: u32,
+    rcv_wnd: u32,
+    rmt_wnd: u32,
+    cwnd: u32,
+    probe: u32,
+    current: u32,
+    interval: u32,
+    ts_flush: u32,
+    xmit: u32,
+    nodelay: u32,
+    updated: u32,
+    ts_probe: u32,
+    probe_wait: u32,
+    dead_link: u32,
+    incr: u32,
+    snd_queue: VecDeque<Segment>,
+    rcv_queue: VecDeque<Segment>,
+    snd_buf: VecDeque<Segment>,
+    rcv_buf: VecDeque<Segment>,
+    acklist: Vec<u32>,
+    fastresend: i32,
+    nocwnd: i32,
+    stream: bool,
+    sack: bool,
+    rmt_sack: bool,
+    sack_adv: u32,
+    ack_policy: AckPolicy,
+    ack_piggyback: bool,
+    ts_ack: u32,
+    max_message: usize,
+    rcv_partial: Vec<u8>,
+    rcv_discard: bool,
+    rcv_skip: bool,
+    unordered: bool,
+    rcv_inorder: bool,
+    snd_split: bool,
+    pmtu_max: u32,
+    pmtu_min: u32,
+    pmtu_high: u32,
+    pmtu_probe: u32,
+    pmtu_sent: u32,
+    ts_pmtu: u32,
+    pmtu_reply: Option<(u32, u32)>,
+    pacing: Pacing,
+    pace_credit: i32,
+    ts_pace: u32,
+    /// rate and burst of the own rate limiter
+    rate_limit: Option<(u32, u32)>,
+    throttled: u32,
+    ts_throttle: Option<u32>,
+    snd_bytes: usize,
+    snd_high_bytes: usize,
+    snd_low_bytes: usize,
+    snd_high_segments: usize,
+    snd_low_segments: usize,
+    snd_blocked: bool,
+    snd_unreliable: VecDeque<Vec<u8>>,
+    rcv_unreliable: VecDeque<Vec<u8>>,
+}
+
+impl KcpSnapshot {
+    /// the format version, SNAPSHOT_VERSION when taken by this crate
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// the conversation id of the session
+    pub fn conv(&self) -> u32 {
+        self.conv
+    }
+}
+
+/// the output callback of a session
+type OutputFn = Box<dyn FnMut(&mut ByteBuffer, usize)>;
 
 #[derive(Default)]
 pub struct KCP {
@@ -82,14 +270,94 @@ pub struct KCP {
 
     snd_queue: VecDeque<Segment>,
     rcv_queue: VecDeque<Segment>,
-    snd_buf: VecDeque<Segment>,
-    rcv_buf: VecDeque<Segment>,
+    snd_buf: SegmentRing,
+    rcv_buf: SegmentRing,
 
     acklist: Vec<u32>,
     buffer: ByteBuffer,
     fastresend: i32,
     nocwnd: i32,
-    on_update: Option<Box<FnMut(&mut ByteBuffer, usize)>>,
+    stream: bool,
+    sack: bool,
+    rmt_sack: bool,
+    sack_adv: u32,
+    ack_policy: AckPolicy,
+    ack_piggyback: bool,
+    /// when the oldest entry of acklist was queued
+    ts_ack: u32,
+    /// 0 keeps the 255 fragments limit, both peers must agree otherwise
+    max_message: usize,
+    /// the fragments beginning the next message, moved out of rcv_queue
+    rcv_partial: Vec<Bytes>,
+    /// the bytes in rcv_partial
+    rcv_partial_len: usize,
+    /// the large message being received exceeded max_message
+    rcv_discard: bool,
+    /// dropping the fragments of a message the sender skipped
+    rcv_skip: bool,
+    unordered: bool,
+    /// unordered mode: fragments received and total (0 until the head
+    /// arrived) of the messages in rcv_buf, by the sn of their last fragment
+    rcv_messages: BTreeMap<u32, (u32, u32)>,
+    /// unordered mode: first sn and fragment count of the complete
+    /// messages waiting for room in rcv_queue
+    rcv_complete: VecDeque<(u32, u32)>,
+    /// unordered mode: a message larger than rcv_wnd is moving to
+    /// rcv_queue in order, the complete ones wait until it is done
+    rcv_inorder: bool,
+    /// snd_queue starts with the remaining fragments of a message
+    snd_split: bool,
+    /// the largest MTU to probe for, 0 if discovery is off
+    pmtu_max: u32,
+    /// the MTU discovery starts at and falls back to
+    pmtu_min: u32,
+    /// the largest MTU not known to be too large
+    pmtu_high: u32,
+    /// the size of the probe waiting for an ack, 0 if none
+    pmtu_probe: u32,
+    /// how many times pmtu_probe was sent
+    pmtu_sent: u32,
+    /// when the probe times out or the next search starts
+    ts_pmtu: u32,
+    /// size and ts of the last probe received, to be acked
+    pmtu_reply: Option<(u32, u32)>,
+    /// sn, length and data of the segment being received in CMD_PIECE
+    /// parts, not kept in snapshots
+    rcv_piece: Option<(u32, usize, Vec<u8>)>,
+    pacing: Pacing,
+    /// bytes the pacer lets through before the next refill
+    pace_credit: i32,
+    /// when pace_credit was last refilled
+    ts_pace: u32,
+    /// this session's own token bucket
+    rate_limit: Option<RateLimiter>,
+    /// a token bucket shared with other sessions
+    shared_limit: Option<RateLimiter>,
+    /// total millisec data was held back by a rate limiter
+    throttled: u32,
+    /// since when data is held back by a rate limiter
+    ts_throttle: Option<u32>,
+    /// payload bytes in snd_queue and snd_buf
+    snd_bytes: usize,
+    /// send blocks at high and is writable again at low, 0 if unlimited
+    snd_high_bytes: usize,
+    snd_low_bytes: usize,
+    snd_high_segments: usize,
+    snd_low_segments: usize,
+    /// send returned ERR_WOULD_BLOCK since the last on_writable
+    snd_blocked: bool,
+    on_writable: Option<Box<dyn FnMut()>>,
+    /// datagrams of send_unreliable waiting for the next flush
+    snd_unreliable: VecDeque<Vec<u8>>,
+    /// datagrams received for recv_unreliable
+    rcv_unreliable: VecDeque<Vec<u8>>,
+    pool: Option<BufferPool>,
+    allocs: AllocStats,
+    /// the probe padding and the SACK bitmap are put together here
+    scratch: Vec<u8>,
+    /// the room of the segment containers when count_growth last looked
+    capacity: usize,
+    on_update: Option<OutputFn>,
 }
 
 impl KCP {
@@ -97,28 +365,209 @@ impl KCP {
         where F: FnMut(&mut ByteBuffer, usize),
               F: 'static
     {
-        let mut kcp: KCP = Default::default();
-        kcp.conv = conv;
-        kcp.snd_wnd = WND_SND;
-        kcp.rcv_wnd = WND_RCV;
-        kcp.rmt_wnd = WND_RCV;
-        kcp.mtu = MTU_DEF;
-        kcp.mss = MTU_DEF - OVERHEAD;
-        kcp.rx_rto = RTO_DEF;
-        kcp.rx_minrto = RTO_MIN;
-        kcp.interval = INTERVAL;
-        kcp.ts_flush = INTERVAL;
-        kcp.ssthresh = THRESH_INIT;
-        kcp.dead_link = DEADLINK;
-        kcp.buffer = ByteBuffer::with_capacity(((MTU_DEF + OVERHEAD) * 3) as usize);
+        let mut kcp = KCP {
+            conv,
+            snd_wnd: WND_SND,
+            rcv_wnd: WND_RCV,
+            rmt_wnd: WND_RCV,
+            mtu: MTU_DEF,
+            mss: MTU_DEF - OVERHEAD,
+            rx_rto: RTO_DEF,
+            rx_minrto: RTO_MIN,
+            interval: INTERVAL,
+            ts_flush: INTERVAL,
+            ssthresh: THRESH_INIT,
+            dead_link: DEADLINK,
+            ..Default::default()
+        };
+        kcp.reserve_buffer(MTU_DEF);
         kcp.on_update = Some(Box::new(f));
         kcp
     }
 
+    /// the conversation id of this session
+    pub fn conv(&self) -> u32 {
+        self.conv
+    }
+
+    /// capture the protocol state, queues included, see KcpSnapshot
+    pub fn snapshot(&self) -> KcpSnapshot {
+        KcpSnapshot {
+            version: SNAPSHOT_VERSION,
+            conv: self.conv,
+            mtu: self.mtu,
+            mss: self.mss,
+            state: self.state,
+            snd_una: self.snd_una,
+            snd_nxt: self.snd_nxt,
+            rcv_nxt: self.rcv_nxt,
+            ssthresh: self.ssthresh,
+            rx_rttval: self.rx_rttval,
+            rx_srtt: self.rx_srtt,
+            rx_rto: self.rx_rto,
+            rx_minrto: self.rx_minrto,
+            snd_wnd: self.snd_wnd,
+            rcv_wnd: self.rcv_wnd,
+            rmt_wnd: self.rmt_wnd,
+            cwnd: self.cwnd,
+            probe: self.probe,
+            current: self.current,
+            interval: self.interval,
+            ts_flush: self.ts_flush,
+            xmit: self.xmit,
+            nodelay: self.nodelay,
+            updated: self.updated,
+            ts_probe: self.ts_probe,
+            probe_wait: self.probe_wait,
+            dead_link: self.dead_link,
+            incr: self.incr,
+            snd_queue: self.snd_queue.clone(),
+            rcv_queue: self.rcv_queue.clone(),
+            snd_buf: self.snd_buf.iter().cloned().collect(),
+            rcv_buf: self.rcv_buf.iter().cloned().collect(),
+            acklist: self.acklist.clone(),
+            fastresend: self.fastresend,
+            nocwnd: self.nocwnd,
+            stream: self.stream,
+            sack: self.sack,
+            rmt_sack: self.rmt_sack,
+            sack_adv: self.sack_adv,
+            ack_policy: self.ack_policy,
+            ack_piggyback: self.ack_piggyback,
+            ts_ack: self.ts_ack,
+            max_message: self.max_message,
+            rcv_partial: self.rcv_partial.iter().flat_map(|data| data.iter().cloned()).collect(),
+            rcv_discard: self.rcv_discard,
+            rcv_skip: self.rcv_skip,
+            unordered: self.unordered,
+            rcv_inorder: self.rcv_inorder,
+            snd_split: self.snd_split,
+            pmtu_max: self.pmtu_max,
+            pmtu_min: self.pmtu_min,
+            pmtu_high: self.pmtu_high,
+            pmtu_probe: self.pmtu_probe,
+            pmtu_sent: self.pmtu_sent,
+            ts_pmtu: self.ts_pmtu,
+            pmtu_reply: self.pmtu_reply,
+            pacing: self.pacing,
+            pace_credit: self.pace_credit,
+            ts_pace: self.ts_pace,
+            rate_limit: self.rate_limit.as_ref().map(|limiter| limiter.rate()),
+            throttled: self.throttled,
+            ts_throttle: self.ts_throttle,
+            snd_bytes: self.snd_bytes,
+            snd_high_bytes: self.snd_high_bytes,
+            snd_low_bytes: self.snd_low_bytes,
+            snd_high_segments: self.snd_high_segments,
+            snd_low_segments: self.snd_low_segments,
+            snd_blocked: self.snd_blocked,
+            snd_unreliable: self.snd_unreliable.clone(),
+            rcv_unreliable: self.rcv_unreliable.clone(),
+        }
+    }
+
+    /// resume a session from a snapshot, sending its packets to `f`.
+    /// None if the snapshot was written by an incompatible version
+    pub fn restore<F>(snapshot: KcpSnapshot, f: F) -> Option<Self>
+        where F: FnMut(&mut ByteBuffer, usize),
+              F: 'static
+    {
+        if snapshot.version != SNAPSHOT_VERSION {
+            return None;
+        }
+        let mut kcp = KCP {
+            conv: snapshot.conv,
+            mtu: snapshot.mtu,
+            mss: snapshot.mss,
+            state: snapshot.state,
+            snd_una: snapshot.snd_una,
+            snd_nxt: snapshot.snd_nxt,
+            rcv_nxt: snapshot.rcv_nxt,
+            ssthresh: snapshot.ssthresh,
+            rx_rttval: snapshot.rx_rttval,
+            rx_srtt: snapshot.rx_srtt,
+            rx_rto: snapshot.rx_rto,
+            rx_minrto: snapshot.rx_minrto,
+            snd_wnd: snapshot.snd_wnd,
+            rcv_wnd: snapshot.rcv_wnd,
+            rmt_wnd: snapshot.rmt_wnd,
+            cwnd: snapshot.cwnd,
+            probe: snapshot.probe,
+            current: snapshot.current,
+            interval: snapshot.interval,
+            ts_flush: snapshot.ts_flush,
+            xmit: snapshot.xmit,
+            nodelay: snapshot.nodelay,
+            updated: snapshot.updated,
+            ts_probe: snapshot.ts_probe,
+            probe_wait: snapshot.probe_wait,
+            dead_link: snapshot.dead_link,
+            incr: snapshot.incr,
+            snd_queue: snapshot.snd_queue,
+            rcv_queue: snapshot.rcv_queue,
+            acklist: snapshot.acklist,
+            fastresend: snapshot.fastresend,
+            nocwnd: snapshot.nocwnd,
+            stream: snapshot.stream,
+            sack: snapshot.sack,
+            rmt_sack: snapshot.rmt_sack,
+            sack_adv: snapshot.sack_adv,
+            ack_policy: snapshot.ack_policy,
+            ack_piggyback: snapshot.ack_piggyback,
+            ts_ack: snapshot.ts_ack,
+            max_message: snapshot.max_message,
+            rcv_partial_len: snapshot.rcv_partial.len(),
+            rcv_discard: snapshot.rcv_discard,
+            rcv_skip: snapshot.rcv_skip,
+            unordered: snapshot.unordered,
+            rcv_inorder: snapshot.rcv_inorder,
+            snd_split: snapshot.snd_split,
+            pmtu_max: snapshot.pmtu_max,
+            pmtu_min: snapshot.pmtu_min,
+            pmtu_high: snapshot.pmtu_high,
+            pmtu_probe: snapshot.pmtu_probe,
+            pmtu_sent: snapshot.pmtu_sent,
+            ts_pmtu: snapshot.ts_pmtu,
+            pmtu_reply: snapshot.pmtu_reply,
+            pacing: snapshot.pacing,
+            pace_credit: snapshot.pace_credit,
+            ts_pace: snapshot.ts_pace,
+            rate_limit: snapshot.rate_limit.map(|(rate, burst)| RateLimiter::new(rate, burst)),
+            throttled: snapshot.throttled,
+            ts_throttle: snapshot.ts_throttle,
+            snd_bytes: snapshot.snd_bytes,
+            snd_high_bytes: snapshot.snd_high_bytes,
+            snd_low_bytes: snapshot.snd_low_bytes,
+            snd_high_segments: snapshot.snd_high_segments,
+            snd_low_segments: snapshot.snd_low_segments,
+            snd_blocked: snapshot.snd_blocked,
+            snd_unreliable: snapshot.snd_unreliable,
+            rcv_unreliable: snapshot.rcv_unreliable,
+            ..Default::default()
+        };
+        for seg in snapshot.snd_buf {
+            kcp.snd_buf.insert(seg);
+        }
+        for seg in snapshot.rcv_buf {
+            let (cmd, sn, frg) = (seg.cmd, seg.sn, seg.frg);
+            kcp.rcv_buf.insert(seg);
+            if snapshot.unordered {
+                kcp.track_fragment(cmd, sn, frg);
+            }
+        }
+        if !snapshot.rcv_partial.is_empty() {
+            kcp.rcv_partial.push(Bytes::from(snapshot.rcv_partial));
+        }
+        let mtu = max(kcp.mtu, kcp.pmtu_max);
+        kcp.reserve_buffer(mtu);
+        kcp.on_update = Some(Box::new(f));
+        Some(kcp)
+    }
+
     pub fn peek_size(&self) -> isize {
-        if let Some(seg) = self.rcv_queue.get(0) {
+        if let Some(seg) = self.rcv_queue.front() {
             if seg.frg == 0 {
-                return seg.data.len() as isize;
+                return (self.rcv_partial_len + seg.data.len()) as isize;
             }
             if self.rcv_queue.len() < ((seg.frg + 1) as usize) {
                 return -1;
@@ -126,14 +575,14 @@ impl KCP {
         } else {
             return -1;
         }
-        let mut length: usize = 0;
+        let mut length: usize = self.rcv_partial_len;
         for seg in &self.rcv_queue {
             length += seg.data.len();
             if seg.frg == 0 {
-                break;
+                return length as isize;
             }
         }
-        length as isize
+        -1
     }
 
     pub fn recv(&mut self, buffer: &mut ByteBuffer) -> isize {
@@ -148,80 +597,391 @@ impl KCP {
             return -3;
         }
         let fast_recover = self.rcv_queue.len() >= self.rcv_wnd as usize;
-        let mut num: usize = 0;
-        while let Some(ref mut seg) = self.rcv_queue.pop_front() {
+        let mut num: usize = self.rcv_partial_len;
+        for data in &self.rcv_partial {
+            buffer.write_bytes(data).unwrap();
+        }
+        self.clear_partial();
+        while let Some(seg) = self.rcv_queue.pop_front() {
             buffer.write_bytes(&seg.data).unwrap();
             num += seg.data.len();
+            let last = seg.frg == 0;
+            self.recycle(seg.data);
+            if last {
+                break;
+            }
+        }
+        self.received(fast_recover);
+        num as isize
+    }
+
+    /// like recv, but copies the next message without taking it from the
+    /// receive queue
+    pub fn peek(&self, buffer: &mut ByteBuffer) -> isize {
+        if self.rcv_queue.is_empty() {
+            return -1;
+        }
+        let peeksize = self.peek_size();
+        if peeksize < 0 {
+            return -2;
+        }
+        if peeksize as usize > buffer.len() {
+            return -3;
+        }
+        for data in &self.rcv_partial {
+            buffer.write_bytes(data).unwrap();
+        }
+        for seg in &self.rcv_queue {
+            buffer.write_bytes(&seg.data).unwrap();
             if seg.frg == 0 {
                 break;
             }
         }
-        while let Some(seg) = self.rcv_buf.pop_front() {
-            if seg.sn == self.rcv_nxt && self.rcv_queue.len() < self.rcv_wnd as usize {
-                self.rcv_queue.push_back(seg);
-                self.rcv_nxt += 1;
+        peeksize
+    }
+
+    /// like recv, returning the message itself: a message of one segment
+    /// is the received payload, a larger one is copied together once.
+    /// None if no complete message is waiting
+    pub fn recv_bytes(&mut self) -> Option<Bytes> {
+        let size = self.peek_size();
+        if size < 0 {
+            return None;
+        }
+        let fast_recover = self.rcv_queue.len() >= self.rcv_wnd as usize;
+        let data = if self.rcv_partial.is_empty() && self.rcv_queue[0].frg == 0 {
+            self.rcv_queue.pop_front().unwrap().data
+        } else {
+            let mut data = Vec::with_capacity(size as usize);
+            for partial in &self.rcv_partial {
+                data.extend_from_slice(partial);
+            }
+            self.clear_partial();
+            while let Some(seg) = self.rcv_queue.pop_front() {
+                data.extend_from_slice(&seg.data);
+                let last = seg.frg == 0;
+                self.recycle(seg.data);
+                if last {
+                    break;
+                }
+            }
+            self.allocs.payloads += 1;
+            Bytes::from(data)
+        };
+        self.received(fast_recover);
+        Some(data)
+    }
+
+    /// like recv_bytes, but leaves the message in the receive queue
+    pub fn peek_bytes(&self) -> Option<Bytes> {
+        let size = self.peek_size();
+        if size < 0 {
+            return None;
+        }
+        if self.rcv_partial.is_empty() && self.rcv_queue[0].frg == 0 {
+            return Some(self.rcv_queue[0].data.clone());
+        }
+        let mut data = Vec::with_capacity(size as usize);
+        for partial in &self.rcv_partial {
+            data.extend_from_slice(partial);
+        }
+        for seg in &self.rcv_queue {
+            data.extend_from_slice(&seg.data);
+            if seg.frg == 0 {
+                break;
             }
         }
+        Some(Bytes::from(data))
+    }
+
+    /// after a message was taken from rcv_queue, refill it and tell the
+    /// peer if the window opened again
+    fn received(&mut self, fast_recover: bool) {
+        self.collect_partial();
+        self.move_to_rcv_queue();
+        self.count_growth();
         if self.rcv_queue.len() < self.rcv_wnd as usize && fast_recover {
             self.probe |= ASK_TELL;
         }
-        num as isize
     }
 
     pub fn send(&mut self, buffer: &mut ByteBuffer) -> isize {
-        if buffer.len() == 0 {
+        self.send_message(buffer, None)
+    }
+
+    /// like send, the segments keep slices of `data` instead of copies
+    pub fn send_bytes(&mut self, data: Bytes) -> isize {
+        let ret = self.can_send(data.len());
+        if ret < 0 {
+            return ret;
+        }
+        self.queue_message(data, None, false);
+        0
+    }
+
+    /// send a message that is worthless `ttl` millisec after the last
+    /// update: once expired it is no longer (re)transmitted and the peer
+    /// skips it, so it stops holding up later messages. Both peers must
+    /// support CMD_SKIP, not available in stream mode
+    pub fn send_with_deadline(&mut self, buffer: &mut ByteBuffer, ttl: u32) -> isize {
+        if self.stream {
+            return -3;
+        }
+        let expire = self.current.wrapping_add(ttl);
+        self.send_message(buffer, Some(expire))
+    }
+
+    fn send_message(&mut self, buffer: &mut ByteBuffer, expire: Option<u32>) -> isize {
+        let len = buffer.read_remain();
+        let ret = self.can_send(len);
+        if ret < 0 {
+            return ret;
+        }
+        let data = Bytes::from(buffer.read_bytes(len).unwrap());
+        let pooled = self.pool.is_some();
+        if pooled {
+            self.allocs.reads += 1;
+        } else {
+            self.allocs.payloads += 1;
+        }
+        self.queue_message(data, expire, pooled);
+        0
+    }
+
+    /// 0 if a message of `len` bytes can be queued, else the error of send
+    fn can_send(&mut self, mut len: usize) -> isize {
+        if len == 0 {
             return -1;
         }
-        let mut count: usize = if buffer.len() < self.mss as usize {
+        if self.above_high_watermark() {
+            self.snd_blocked = true;
+            return ERR_WOULD_BLOCK;
+        }
+        // the part appended to the previous segment in streaming mode
+        if self.stream {
+            if let Some(seg) = self.snd_queue.back() {
+                if seg.data.len() < self.mss as usize {
+                    len -= min(len, self.mss as usize - seg.data.len());
+                }
+            }
+            if len == 0 {
+                return 0;
+            }
+        }
+        let count: usize = if len < self.mss as usize {
             1
         } else {
-            (buffer.len() + (self.mss as usize) - 1) / (self.mss as usize)
+            len.div_ceil(self.mss as usize)
         };
-        if count > 255 {
+        if (count > 255 && (self.stream || self.max_message == 0 || self.unordered)) ||
+           (self.max_message > 0 && len > self.max_message) {
             return -2;
         }
-        if count == 0 {
-            count = 1;
+        0
+    }
+
+    /// `pooled`: copy the segments into buffers of the pool
+    fn queue_message(&mut self, mut data: Bytes, expire: Option<u32>, pooled: bool) {
+        self.snd_bytes += data.len();
+        // append to previous segment in streaming mode (if possible)
+        if self.stream {
+            if let Some(seg) = self.snd_queue.back_mut() {
+                if seg.data.len() < self.mss as usize {
+                    let extend = min(data.len(), self.mss as usize - seg.data.len());
+                    let mut merged = Vec::with_capacity(seg.data.len() + extend);
+                    merged.extend_from_slice(&seg.data);
+                    merged.extend_from_slice(&data.split_to(extend));
+                    seg.data = Bytes::from(merged);
+                    self.allocs.payloads += 1;
+                }
+            }
+            if data.is_empty() {
+                return;
+            }
         }
+        self.push_message(data, expire, pooled);
+        self.count_growth();
+    }
+
+    /// split one message into segments of at most mss bytes on snd_queue
+    fn push_message(&mut self, data: Bytes, expire: Option<u32>, pooled: bool) {
+        let mss = self.mss as usize;
+        let count = max(1, data.len().div_ceil(mss));
         for i in 0..count {
-            let size = min(buffer.read_remain(), self.mss as usize);
-            let mut seg = Segment::from_bytes(&buffer.read_bytes(size).unwrap());
-            seg.frg = (count - i - 1) as u32;
+            let end = min(data.len(), (i + 1) * mss);
+            let mut seg = Segment::from_bytes(if pooled {
+                self.pooled(&data[i * mss..end])
+            } else {
+                data.slice(i * mss..end)
+            });
+            let remain = count - i - 1;
+            seg.frg = if self.stream {
+                0
+            } else {
+                (remain % 256) as u32
+            };
+            // only messages allowed by max_message count fragments past
+            // 255, stream segments carry no fragment numbers at all
+            seg.cmd = if remain >= 256 && self.max_message > 0 && !self.stream {
+                CMD_PUSH_CONT
+            } else if i == 0 && self.unordered && !self.stream {
+                CMD_PUSH_HEAD
+            } else {
+                CMD_PUSH
+            };
+            seg.expire = expire;
             self.snd_queue.push_back(seg);
         }
+    }
+
+    /// split the data waiting in snd_queue again after mss changed; the
+    /// rest of a message already partly sent keeps its fragments, as does
+    /// a message that would need more than 255 of them
+    fn resegment(&mut self) {
+        if self.stream {
+            let mut data = Vec::new();
+            for seg in self.snd_queue.drain(..) {
+                data.extend_from_slice(&seg.data);
+            }
+            if !data.is_empty() {
+                self.allocs.payloads += 1;
+            }
+            // in pieces of at most 255 segments, like send accepts them
+            let data = Bytes::from(data);
+            let chunk = 255 * self.mss as usize;
+            for start in (0..data.len()).step_by(chunk) {
+                let end = min(data.len(), start + chunk);
+                self.push_message(data.slice(start..end), None, false);
+            }
+            return;
+        }
+        // the queue is rotated in place, what is left of the old segments
+        // stays in front of the new ones
+        let mut remain = self.snd_queue.len();
+        if self.snd_split {
+            while remain > 0 {
+                remain -= 1;
+                let seg = self.snd_queue.pop_front().unwrap();
+                let last = ends_message(&seg);
+                self.snd_queue.push_back(seg);
+                if last {
+                    break;
+                }
+            }
+        }
+        let mut message: Vec<Segment> = Vec::new();
+        while remain > 0 {
+            remain -= 1;
+            let seg = self.snd_queue.pop_front().unwrap();
+            let last = ends_message(&seg);
+            message.push(seg);
+            if !last && remain > 0 {
+                continue;
+            }
+            let len = message.iter().map(|seg| seg.data.len()).sum::<usize>();
+            if len.div_ceil(self.mss as usize) > 255 && (self.max_message == 0 || self.unordered) {
+                self.snd_queue.extend(message.drain(..));
+            } else {
+                let expire = message[0].expire;
+                let mut data = Vec::with_capacity(len);
+                for seg in message.drain(..) {
+                    data.extend_from_slice(&seg.data);
+                }
+                self.allocs.payloads += 1;
+                self.push_message(Bytes::from(data), expire, false);
+            }
+        }
+    }
+
+    /// queue a datagram for the next flush, outside of the reliable
+    /// stream: it is not acked, retransmitted or ordered, but counts
+    /// against the congestion window, pacing and rate limits like data.
+    /// It has to fit in one packet, and when snd_wnd datagrams are already
+    /// waiting the oldest is dropped. Both peers must support it
+    pub fn send_unreliable(&mut self, buffer: &mut ByteBuffer) -> isize {
+        let len = buffer.read_remain();
+        if len == 0 {
+            return -1;
+        }
+        if len > self.mss as usize {
+            return -2;
+        }
+        if self.snd_unreliable.len() >= self.snd_wnd as usize {
+            self.snd_unreliable.pop_front();
+        }
+        self.snd_unreliable.push_back(buffer.read_bytes(len).unwrap());
+        self.allocs.payloads += 1;
         0
     }
 
+    /// take the next datagram sent by the peer's send_unreliable,
+    /// -1 if none is waiting, -3 if buffer is too small for it
+    pub fn recv_unreliable(&mut self, buffer: &mut ByteBuffer) -> isize {
+        let len = match self.rcv_unreliable.front() {
+            Some(data) => data.len(),
+            None => return -1,
+        };
+        if len > buffer.len() {
+            return -3;
+        }
+        let data = self.rcv_unreliable.pop_front().unwrap();
+        buffer.write_bytes(&data).unwrap();
+        len as isize
+    }
+
     /// when you received a low level packet (eg. UDP packet), call it
     pub fn input(&mut self, data: &mut ByteBuffer) -> isize {
+        let len = data.read_remain();
+        if len < OVERHEAD as usize {
+            return -1;
+        }
+        let packet = data.read_bytes(len).unwrap();
+        self.allocs.reads += 1;
+        self.input_slice(&packet)
+    }
+
+    /// like input, for a packet not in a ByteBuffer. Payloads are copied
+    /// straight into buffers of the pool, so with one nothing is allocated
+    pub fn input_slice(&mut self, packet: &[u8]) -> isize {
         let una = self.snd_una;
-        if data.len() < OVERHEAD as usize {
+        if packet.len() < OVERHEAD as usize {
             return -1;
         }
+        let mut data = Reader { data: packet };
         let mut maxack: u32 = 0;
         let mut flag: isize = 0;
 
         loop {
-            if data.len() < OVERHEAD as usize {
+            if data.remain() < OVERHEAD as usize {
                 break;
             }
-            let conv = data.read_u32().unwrap();
+            let conv = data.u32();
             if conv != self.conv {
                 return -1;
             }
-            let cmd = data.read_u8_as_u32().unwrap();
-            let frg = data.read_u8_as_u32().unwrap();
-            let wnd = data.read_u16_as_u32().unwrap();
-            let ts = data.read_u32().unwrap();
-            let sn = data.read_u32().unwrap();
-            let una = data.read_u32().unwrap();
-            let length = data.read_u32().unwrap();
-            if data.read_remain() < length as usize {
+            let cmd = data.u8();
+            let frg = data.u8();
+            let wnd = data.u16();
+            let ts = data.u32();
+            let sn = data.u32();
+            let una = data.u32();
+            let length = data.u32();
+            if data.remain() < length as usize {
                 return -2;
             }
-            if cmd != CMD_PUSH && cmd != CMD_ACK && cmd != CMD_WASK && cmd != CMD_WINS {
+            if cmd != CMD_PUSH && cmd != CMD_ACK && cmd != CMD_WASK && cmd != CMD_WINS &&
+               cmd != CMD_SACK && cmd != CMD_MTU_PROBE && cmd != CMD_MTU_ACK && cmd != CMD_SKIP &&
+               cmd != CMD_UNRELIABLE && cmd != CMD_PUSH_HEAD && cmd != CMD_PIECE &&
+               (cmd != CMD_PUSH_CONT || self.max_message == 0) {
                 return -3;
             }
+            let push = cmd == CMD_PUSH || cmd == CMD_PUSH_CONT || cmd == CMD_PUSH_HEAD ||
+                       cmd == CMD_SKIP;
+            // only acks and window probes carry the flag in frg
+            let acking = cmd == CMD_ACK || cmd == CMD_WASK || cmd == CMD_WINS || cmd == CMD_SACK;
+            if acking && (frg & FRG_SACK) != 0 {
+                self.rmt_sack = true;
+            }
             self.rmt_wnd = wnd;
             self.parse_una(una);
             self.shrink_buf();
@@ -239,12 +999,12 @@ impl KCP {
                 } else if sn > maxack {
                     maxack = sn;
                 }
-            } else if cmd == CMD_PUSH {
+            } else if push {
+                let payload = data.bytes(length as usize);
                 if sn < (self.rcv_nxt + self.rcv_wnd) {
                     self.ack_push(sn, ts);
-                    if sn >= self.rcv_nxt {
-                        let mut seg = Segment::from_bytes(&data.read_bytes(length as usize)
-                            .unwrap());
+                    if sn >= self.rcv_nxt && !self.rcv_buf.contains(sn) {
+                        let mut seg = Segment::from_bytes(self.payload(payload));
                         seg.conv = conv;
                         seg.cmd = cmd;
                         seg.frg = frg;
@@ -255,11 +1015,75 @@ impl KCP {
                         self.parse_data(seg);
                     }
                 }
+            } else if cmd == CMD_SACK {
+                let bitmap = data.bytes(length as usize);
+                if self.current >= ts {
+                    let rtt = sub_u32(self.current, ts) as u32;
+                    self.update_ack(rtt);
+                }
+                for (i, byte) in bitmap.iter().enumerate() {
+                    for bit in 0..8 {
+                        if byte & (1 << bit) != 0 {
+                            let sn = sn.wrapping_add((i * 8 + bit) as u32);
+                            if sn < self.snd_una || sn >= self.snd_nxt {
+                                continue;
+                            }
+                            self.parse_ack(sn);
+                            if flag == 0 || sn > maxack {
+                                flag = 1;
+                                maxack = sn;
+                            }
+                        }
+                    }
+                }
+                self.shrink_buf();
             } else if cmd == CMD_WASK {
                 // ready to send back CMD_WINS in self.flush
                 self.probe |= ASK_TELL;
             } else if cmd == CMD_WINS {
 
+            } else if cmd == CMD_MTU_PROBE {
+                // the padding only has to get here, ack it in self.flush
+                data.bytes(length as usize);
+                self.pmtu_reply = Some((sn, ts));
+            } else if cmd == CMD_MTU_ACK {
+                self.parse_mtu_ack(sn);
+            } else if cmd == CMD_PIECE {
+                if length < PIECE_OVERHEAD {
+                    return -2;
+                }
+                let orig = data.u8();
+                let offset = data.u32() as usize;
+                let total = data.u32() as usize;
+                let part = data.bytes((length - PIECE_OVERHEAD) as usize);
+                if (orig != CMD_PUSH && orig != CMD_PUSH_HEAD && orig != CMD_SKIP &&
+                    (orig != CMD_PUSH_CONT || self.max_message == 0)) ||
+                   total > 0xffff {
+                    return -3;
+                }
+                if sn < (self.rcv_nxt + self.rcv_wnd) {
+                    if sn < self.rcv_nxt || self.rcv_buf.contains(sn) {
+                        self.ack_push(sn, ts);
+                    } else if let Some(payload) = self.parse_piece(sn, offset, total, part) {
+                        self.ack_push(sn, ts);
+                        let mut seg = Segment::from_bytes(self.payload(&payload));
+                        seg.conv = conv;
+                        seg.cmd = orig;
+                        seg.frg = frg;
+                        seg.wnd = wnd;
+                        seg.ts = ts;
+                        seg.sn = sn;
+                        seg.una = una;
+                        self.parse_data(seg);
+                    }
+                }
+            } else if cmd == CMD_UNRELIABLE {
+                let payload = data.bytes(length as usize).to_vec();
+                self.allocs.payloads += 1;
+                if self.rcv_unreliable.len() >= self.rcv_wnd as usize {
+                    self.rcv_unreliable.pop_front();
+                }
+                self.rcv_unreliable.push_back(payload);
             } else {
                 return -3;
             }
@@ -267,6 +1091,15 @@ impl KCP {
         if flag != 0 {
             self.parse_fastack(maxack);
         }
+        self.check_writable();
+        let pending = (self.acklist.len() / 2) as u32;
+        match self.ack_policy {
+            AckPolicy::NoDelay if pending > 0 => self.flush_ack(),
+            AckPolicy::Delayed { every, .. } if pending > 0 && pending >= every => {
+                self.flush_ack()
+            }
+            _ => {}
+        }
 
         if self.snd_una >= una && self.cwnd < self.rmt_wnd {
             let mss = self.mss;
@@ -287,6 +1120,7 @@ impl KCP {
                 self.incr = self.rmt_wnd * mss;
             }
         }
+        self.count_growth();
         0
     }
 
@@ -300,7 +1134,7 @@ impl KCP {
             self.ts_flush = self.current;
         }
         let mut slap = sub_u32(self.current, self.ts_flush);
-        if slap >= 10000 || slap < -10000 {
+        if !(-10000..10000).contains(&slap) {
             self.ts_flush = self.current;
             slap = 0;
         }
@@ -310,6 +1144,12 @@ impl KCP {
                 self.ts_flush = self.current + self.interval;
             }
             self.flush();
+        } else if self.send_wait(self.current) == Some(0) {
+            self.flush();
+        } else if let AckPolicy::Delayed { max_delay, .. } = self.ack_policy {
+            if !self.acklist.is_empty() && sub_u32(self.current, self.ts_ack) >= max_delay as i32 {
+                self.flush_ack();
+            }
         }
     }
 
@@ -320,19 +1160,34 @@ impl KCP {
     /// Important to reduce unnacessary self.update invoking. use it to
     /// schedule self.update (eg. implementing an epoll-like mechanism,
     /// or optimize self.update when handling massive kcp connections)
-    pub fn check(&mut self, current: u32) -> u32 {
+    pub fn check(&self, current: u32) -> u32 {
         let mut ts_flush = self.ts_flush;
         let mut tm_packet: i32 = i32::MAX;
         if self.updated == 0 {
             return current;
         }
         let slab = sub_u32(current, ts_flush);
-        if slab >= 10000 || slab < -10000 {
+        if !(-10000..10000).contains(&slab) {
             ts_flush = current;
         }
         if current >= ts_flush {
             return current;
         }
+        if let AckPolicy::Delayed { max_delay, .. } = self.ack_policy {
+            if !self.acklist.is_empty() {
+                let diff = sub_u32(self.ts_ack.wrapping_add(max_delay), current);
+                if diff <= 0 {
+                    return current;
+                }
+                tm_packet = diff;
+            }
+        }
+        if let Some(wait) = self.send_wait(current) {
+            if wait == 0 {
+                return current;
+            }
+            tm_packet = min(tm_packet, wait as i32);
+        }
         for seg in &self.snd_buf {
             let diff = sub_u32(seg.resendts, current);
             if diff <= 0 {
@@ -361,10 +1216,40 @@ impl KCP {
         }
         self.mtu = mtu_u32;
         self.mss = mtu_u32 - OVERHEAD;
-        self.buffer = ByteBuffer::with_capacity(((mtu_u32 + OVERHEAD) * 3) as usize);
+        self.reserve_buffer(mtu_u32);
+        self.pmtu_max = 0;
+        self.pmtu_probe = 0;
+        self.resegment();
+        0
+    }
+
+    /// path MTU discovery: start at `min` and probe with padded packets
+    /// for the largest MTU up to `max` that gets through, raising mss each
+    /// time a probe is acked. A size whose probe is lost PMTU_PROBES times
+    /// is given up, and the search is repeated every PMTU_RAISE millisec.
+    /// When data is lost the MTU in use is probed again, if that is lost
+    /// too it falls back to `min` and the search starts over; segments
+    /// already sent at the larger size go out in CMD_PIECE parts.
+    /// Peers without it do not ack the probes, so the MTU stays at `min`
+    pub fn set_mtu_discovery(&mut self, min: isize, max: isize) -> isize {
+        if max < min || self.set_mtu(min) < 0 {
+            return -1;
+        }
+        self.reserve_buffer(max as u32);
+        if max > min {
+            self.pmtu_max = max as u32;
+            self.pmtu_min = min as u32;
+            self.pmtu_high = max as u32;
+            self.ts_pmtu = self.current;
+        }
         0
     }
 
+    /// the MTU in use, changed by path MTU discovery
+    pub fn mtu(&self) -> u32 {
+        self.mtu
+    }
+
     /// NoDelay options
     /// fastest: self.no_delay(kcp, 1, 20, 2, 1)
     /// nodelay: 0:disable(default), 1:enable
@@ -409,67 +1294,377 @@ impl KCP {
         0
     }
 
-    /// return the number of packet is waiting to be sent
-    pub fn wait_snd(&self) -> isize {
-        (self.snd_buf.len() + self.snd_queue.len()) as isize
+    /// stream mode: messages are merged into full segments and `recv`
+    /// returns data without message boundaries, like a TCP stream
+    pub fn set_stream(&mut self, stream: bool) {
+        self.stream = stream;
     }
 
-    /// even -> sn odd -> ts
-    fn ack_push(&mut self, sn: u32, ts: u32) {
-        self.acklist.push(sn);
-        self.acklist.push(ts);
+    /// unordered mode: a message is handed to recv as soon as all of its
+    /// fragments arrived instead of after every earlier message, acking
+    /// and retransmission stay the same. Both peers must enable it, sent
+    /// messages are limited to 255 fragments. A message with more
+    /// fragments than the receive window is delivered in order
+    pub fn set_unordered(&mut self, unordered: bool) {
+        self.unordered = unordered;
     }
 
-    /// get sn and ts from acklist
-    fn ack_get(&self, p: usize) -> (u32, u32) {
-        (self.acklist[p * 2], self.acklist[p * 2 + 1])
+    /// selective acks: once the peer advertised support, all acks of a
+    /// flush go out as one CMD_SACK segment instead of one CMD_ACK per
+    /// received segment; legacy peers keep getting CMD_ACK
+    pub fn set_sack(&mut self, sack: bool) {
+        self.sack = sack;
     }
 
-    fn parse_data(&mut self, new_seg: Segment) {
-        let sn = new_seg.sn;
-        if sn >= (self.rcv_nxt + self.rcv_wnd) || sn < self.rcv_nxt {
-            return;
-        }
-        let length = self.rcv_buf.len();
-        for i in (0..length).rev() {
-            let tsn = self.rcv_buf[i].sn;
-            if sn == tsn {
-                // repeat and discard
-                break;
-            }
-            if sn > tsn {
-                if i + 1 <= length {
-                    self.rcv_buf.insert(i + 1, new_seg);
-                } else {
-                    self.rcv_buf.push_back(new_seg);
-                }
-                break;
-            }
-        }
-        while let Some(seg) = self.rcv_buf.pop_front() {
-            if seg.sn == self.rcv_nxt && (self.rcv_queue.len() as u32) < self.rcv_wnd {
-                self.rcv_queue.push_back(seg);
-                self.rcv_nxt += 1;
-            } else {
-                break;
-            }
-        }
+    /// spread data segments out instead of sending them back to back,
+    /// call update at the times given by check for an even rate
+    pub fn set_pacing(&mut self, pacing: Pacing) {
+        self.pacing = pacing;
+        self.pace_credit = 0;
+        self.ts_pace = self.current;
     }
 
-    fn parse_ack(&mut self, sn: u32) {
-        if sn < self.snd_una || sn >= self.snd_nxt {
-            return;
-        }
-        for i in 0..self.snd_buf.len() {
-            let tsn = self.snd_buf[i].sn;
-            if sn == tsn {
-                self.snd_buf.remove(i);
-                break;
-            }
-            if sn < tsn {
-                break;
-            }
-        }
+    /// cap the bandwidth of this session to `rate` bytes per second with
+    /// bursts of up to `burst` bytes, segments over budget wait in snd_buf;
+    /// a rate of 0 removes the limit
+    pub fn set_rate_limit(&mut self, rate: u32, burst: u32) {
+        self.rate_limit = if rate > 0 {
+            Some(RateLimiter::new(rate, burst))
+        } else {
+            None
+        };
+    }
+
+    /// also take tokens from a limiter shared with other sessions, e.g.
+    /// all sessions of one customer
+    pub fn set_shared_limiter(&mut self, limiter: Option<RateLimiter>) {
+        self.shared_limit = limiter;
+    }
+
+    /// take payload buffers from `pool` and give them back once acked or
+    /// received, e.g. a pool shared with other sessions; None allocates
+    /// them. With a pool messages given to send are copied once more, those
+    /// given to send_bytes are not
+    pub fn set_buffer_pool(&mut self, pool: Option<BufferPool>) {
+        self.pool = pool;
+    }
+
+    /// what the session allocated so far
+    pub fn alloc_stats(&self) -> AllocStats {
+        self.allocs
+    }
+
+    /// total millisec data waited for a rate limiter
+    pub fn throttled_time(&self) -> u32 {
+        match self.ts_throttle {
+            Some(ts) => self.throttled + self.current.wrapping_sub(ts),
+            None => self.throttled,
+        }
+    }
+
+    /// choose when acks are sent, see AckPolicy
+    pub fn set_ack_policy(&mut self, policy: AckPolicy) {
+        self.ack_policy = policy;
+    }
+
+    /// leave out acks of segments below rcv_nxt when a flush also sends
+    /// data, the una carried by every data segment acks them already
+    pub fn set_ack_piggyback(&mut self, piggyback: bool) {
+        self.ack_piggyback = piggyback;
+    }
+
+    /// allow messages needing more than 255 fragments, up to `size` bytes,
+    /// and drop received messages larger than that; 0 restores the 255
+    /// fragments limit. Both peers must enable it, a peer without it
+    /// rejects the packets of large messages
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message = size;
+    }
+
+    /// whether a segment was retransmitted dead_link times without an ack
+    pub fn is_dead_link(&self) -> bool {
+        self.state == u32::MAX
+    }
+
+    /// whether update has nothing to do until the next input, send or
+    /// recv: no data or acks waiting, no window probe and no MTU search.
+    /// A session this idle need not be updated, see UpdateScheduler
+    pub fn is_idle(&self) -> bool {
+        self.updated != 0 && self.snd_queue.is_empty() && self.snd_buf.is_empty() &&
+        self.snd_unreliable.is_empty() && self.acklist.is_empty() && self.probe == 0 &&
+        self.rmt_wnd != 0 && self.ts_throttle.is_none() && self.pmtu_reply.is_none() &&
+        self.pmtu_probe == 0 && (self.pmtu_max == 0 || self.mtu >= self.pmtu_max)
+    }
+
+    /// the configured send window in packets
+    pub fn snd_wnd(&self) -> u32 {
+        self.snd_wnd
+    }
+
+    /// return the number of packet is waiting to be sent
+    pub fn wait_snd(&self) -> isize {
+        (self.snd_buf.len() + self.snd_queue.len()) as isize
+    }
+
+    /// payload bytes waiting to be sent or acked
+    pub fn wait_snd_bytes(&self) -> usize {
+        self.snd_bytes
+    }
+
+    /// send returns ERR_WOULD_BLOCK once `high` bytes wait to be sent or
+    /// acked, until acks bring them down to `low`; 0 disables the limit
+    pub fn set_send_watermarks(&mut self, high: usize, low: usize) {
+        self.snd_high_bytes = high;
+        self.snd_low_bytes = min(low, high);
+        self.check_writable();
+    }
+
+    /// like set_send_watermarks, counting segments as in wait_snd
+    pub fn set_send_segment_watermarks(&mut self, high: usize, low: usize) {
+        self.snd_high_segments = high;
+        self.snd_low_segments = min(low, high);
+        self.check_writable();
+    }
+
+    /// called when send can be used again after it returned
+    /// ERR_WOULD_BLOCK, because the peer acked data, expired data was
+    /// dropped or the watermarks changed, e.g. to wake a waiting writer
+    pub fn set_writable_callback<F>(&mut self, f: F)
+        where F: FnMut(),
+              F: 'static
+    {
+        self.on_writable = Some(Box::new(f));
+    }
+
+    /// whether send accepts data now
+    pub fn is_writable(&self) -> bool {
+        !self.above_high_watermark()
+    }
+
+    fn above_high_watermark(&self) -> bool {
+        let segments = self.wait_snd() as usize;
+        (self.snd_high_bytes > 0 && self.snd_bytes >= self.snd_high_bytes) ||
+        (self.snd_high_segments > 0 && segments >= self.snd_high_segments)
+    }
+
+    /// call on_writable once a blocked sender drained to the low watermarks
+    fn check_writable(&mut self) {
+        let segments = self.wait_snd() as usize;
+        if !self.snd_blocked ||
+           (self.snd_high_bytes > 0 && self.snd_bytes > self.snd_low_bytes) ||
+           (self.snd_high_segments > 0 && segments > self.snd_low_segments) {
+            return;
+        }
+        self.snd_blocked = false;
+        if let Some(ref mut f) = self.on_writable {
+            f();
+        }
+    }
+
+    /// even -> sn odd -> ts
+    fn ack_push(&mut self, sn: u32, ts: u32) {
+        if self.acklist.is_empty() {
+            self.ts_ack = self.current;
+        }
+        self.acklist.push(sn);
+        self.acklist.push(ts);
+    }
+
+    /// get sn and ts from acklist
+    fn ack_get(&self, p: usize) -> (u32, u32) {
+        (self.acklist[p * 2], self.acklist[p * 2 + 1])
+    }
+
+    fn parse_data(&mut self, new_seg: Segment) {
+        let sn = new_seg.sn;
+        if sn >= (self.rcv_nxt + self.rcv_wnd) || sn < self.rcv_nxt {
+            return;
+        }
+        let (cmd, frg) = (new_seg.cmd, new_seg.frg);
+        self.rcv_buf.insert(new_seg);
+        if self.unordered {
+            self.track_fragment(cmd, sn, frg);
+        }
+        self.move_to_rcv_queue();
+    }
+
+    /// unordered mode: count a fragment new in rcv_buf towards its
+    /// message, queue the message in rcv_complete once all arrived
+    fn track_fragment(&mut self, cmd: u32, sn: u32, frg: u32) {
+        if cmd != CMD_PUSH && cmd != CMD_PUSH_HEAD {
+            return;
+        }
+        let last = sn + frg;
+        let (received, total) = {
+            let message = self.rcv_messages.entry(last).or_insert((0, 0));
+            message.0 += 1;
+            if cmd == CMD_PUSH_HEAD {
+                message.1 = frg + 1;
+            }
+            *message
+        };
+        // a peer may send fragments that do not add up to their head, only
+        // the complete message in rcv_buf counts
+        if total > 0 && received >= total && self.holds_message(last + 1 - total, total) {
+            self.rcv_messages.remove(&last);
+            self.rcv_complete.push_back((last + 1 - total, total));
+        }
+    }
+
+    /// whether rcv_buf holds the head at `sn` and all `total` fragments of
+    /// its message
+    fn holds_message(&self, sn: u32, total: u32) -> bool {
+        let last = sn + total - 1;
+        (sn..=last).all(|i| match self.rcv_buf.get(i) {
+            Some(seg) => {
+                let cmd = if i == sn { CMD_PUSH_HEAD } else { CMD_PUSH };
+                seg.cmd == cmd && seg.sn + seg.frg == last
+            }
+            None => false,
+        })
+    }
+
+    /// move available data from rcv_buf -> rcv_queue
+    fn move_to_rcv_queue(&mut self) {
+        if self.unordered {
+            self.deliver_complete();
+        }
+        while let Some((cmd, frg)) = self.rcv_buf.get(self.rcv_nxt).map(|seg| (seg.cmd, seg.frg)) {
+            let dropped = cmd == CMD_DELIVERED || cmd == CMD_SKIP || self.rcv_skip ||
+                          (cmd == CMD_PUSH_HEAD && self.unordered && self.head_skipped());
+            if !dropped &&
+               ((cmd == CMD_PUSH_HEAD && self.unordered && frg < self.rcv_wnd) ||
+                (self.rcv_queue.len() as u32) >= self.rcv_wnd) {
+                // a head waits for the rest of its message in unordered
+                // mode, unless the message cannot fit in the window
+                break;
+            }
+            let seg = self.rcv_buf.remove(self.rcv_nxt).unwrap();
+            self.rcv_nxt += 1;
+            if cmd == CMD_PUSH_HEAD && !dropped {
+                self.rcv_inorder = true;
+            }
+            if ends_message(&seg) {
+                self.rcv_inorder = false;
+            }
+            if seg.cmd == CMD_SKIP {
+                self.drop_partial();
+                self.rcv_skip = seg.frg != 0;
+            } else if dropped {
+                if seg.cmd != CMD_DELIVERED {
+                    self.rcv_skip = !ends_message(&seg);
+                }
+                self.recycle(seg.data);
+            } else {
+                self.rcv_queue.push_back(seg);
+                self.collect_partial();
+            }
+        }
+        // messages rcv_nxt passed before they were complete
+        while let Some(last) = self.rcv_messages.keys().next().cloned() {
+            if last >= self.rcv_nxt {
+                break;
+            }
+            self.rcv_messages.remove(&last);
+        }
+        if self.unordered {
+            self.deliver_complete();
+        }
+    }
+
+    /// unordered mode: move the messages of rcv_complete to rcv_queue as
+    /// long as they fit in rcv_wnd, leaving CMD_DELIVERED in their place
+    /// until rcv_nxt passes them
+    fn deliver_complete(&mut self) {
+        if self.rcv_inorder {
+            return;
+        }
+        while let Some(&(sn, total)) = self.rcv_complete.front() {
+            if !self.rcv_queue.is_empty() &&
+               self.rcv_queue.len() + total as usize > self.rcv_wnd as usize {
+                break;
+            }
+            self.rcv_complete.pop_front();
+            if !self.holds_message(sn, total) {
+                // moved in order meanwhile
+                continue;
+            }
+            for i in sn..sn + total {
+                let mut delivered = Segment::new();
+                delivered.sn = i;
+                delivered.cmd = CMD_DELIVERED;
+                if let Some(seg) = self.rcv_buf.get_mut(i) {
+                    let seg = mem::replace(seg, delivered);
+                    self.rcv_queue.push_back(seg);
+                }
+            }
+        }
+    }
+
+    /// whether a fragment of the message headed by rcv_nxt was skipped
+    fn head_skipped(&self) -> bool {
+        let last = self.rcv_nxt + self.rcv_buf.get(self.rcv_nxt).unwrap().frg;
+        (self.rcv_nxt + 1..last + 1).any(|sn| match self.rcv_buf.get(sn) {
+            Some(seg) => seg.cmd == CMD_SKIP,
+            None => false,
+        })
+    }
+
+    /// forget what arrived of the message a CMD_SKIP gave up on, it is the
+    /// unfinished one at the end of rcv_queue
+    fn drop_partial(&mut self) {
+        while self.rcv_queue.back().is_some_and(|seg| !ends_message(seg)) {
+            self.rcv_queue.pop_back();
+        }
+        if self.rcv_queue.is_empty() {
+            self.clear_partial();
+            self.rcv_discard = false;
+        }
+    }
+
+    /// give the fragments in rcv_partial back to the pool
+    fn clear_partial(&mut self) {
+        while let Some(data) = self.rcv_partial.pop() {
+            self.recycle(data);
+        }
+        self.rcv_partial_len = 0;
+    }
+
+    /// move the leading fragments of rcv_queue that do not end a message
+    /// into rcv_partial, so a message larger than the receive window does
+    /// not hold it forever
+    fn collect_partial(&mut self) {
+        while !self.rcv_queue.is_empty() {
+            let (cmd, frg) = (self.rcv_queue[0].cmd, self.rcv_queue[0].frg);
+            let last = (cmd == CMD_PUSH || cmd == CMD_PUSH_HEAD) && frg == 0;
+            if last && !self.rcv_discard {
+                break;
+            }
+            let seg = self.rcv_queue.pop_front().unwrap();
+            if self.rcv_discard {
+                self.rcv_discard = !last;
+                self.recycle(seg.data);
+            } else if self.max_message > 0 &&
+                      self.rcv_partial_len + seg.data.len() > self.max_message {
+                self.clear_partial();
+                self.rcv_discard = true;
+                self.recycle(seg.data);
+            } else {
+                // kept as it is, copied once when the message is received
+                self.rcv_partial_len += seg.data.len();
+                self.rcv_partial.push(seg.data);
+            }
+        }
+    }
+
+    fn parse_ack(&mut self, sn: u32) {
+        if sn < self.snd_una || sn >= self.snd_nxt {
+            return;
+        }
+        if let Some(seg) = self.snd_buf.remove(sn) {
+            self.snd_bytes -= seg.data.len();
+            self.recycle(seg.data);
+        }
     }
 
     fn parse_fastack(&mut self, sn: u32) {
@@ -486,12 +1681,10 @@ impl KCP {
     }
 
     fn parse_una(&mut self, una: u32) {
-        for i in 0..self.snd_buf.len() {
-            if una > self.snd_buf[i].sn {
-                self.snd_buf.remove(i);
-            } else {
-                break;
-            }
+        while self.snd_buf.front().is_some_and(|seg| una > seg.sn) {
+            let seg = self.snd_buf.pop_front().unwrap();
+            self.snd_bytes -= seg.data.len();
+            self.recycle(seg.data);
         }
     }
 
@@ -502,32 +1695,18 @@ impl KCP {
         0
     }
 
-    fn flush(&mut self) {
+    /// flush pending acks, window probes and data segments to the output
+    pub fn flush(&mut self) {
         if self.updated == 0 {
             return;
         }
         let (current, mut change, mut lost) = (self.current, 0, false);
-        let mut seg = Segment::new();
-        seg.conv = self.conv;
-        seg.cmd = CMD_ACK;
-        seg.wnd = self.wnd_unused() as u32;
-        seg.una = self.rcv_nxt;
+        let mut seg = self.ack_segment();
 
-        // flush ack
-        for i in 0..self.acklist.len() / 2 {
-            let size = self.buffer.get_wpos();
-            if size as u32 + OVERHEAD > self.mtu {
-                if let Some(ref mut f) = self.on_update {
-                    f(&mut self.buffer, size);
-                }
-                self.buffer.clear();
-            }
-            let pair = self.ack_get(i);
-            seg.sn = pair.0;
-            seg.ts = pair.1;
-            seg.encode(&mut self.buffer);
-        }
-        self.acklist.truncate(0);
+        // flush ack, those covered by the una of the data keep waiting in
+        // acklist until it is sure the data is not paced or throttled
+        let piggyback = self.ack_piggyback && self.has_data_to_flush(current);
+        self.flush_acks(&mut seg, piggyback);
         // probe window size (if remote window size equals zero)
         if self.rmt_wnd == 0 {
             if self.probe_wait == 0 {
@@ -549,6 +1728,14 @@ impl KCP {
             self.probe_wait = 0;
         }
 
+        // advertise SACK support while sending data to a peer that has not
+        // shown any yet, since a pure receiver never sees our acks
+        if self.sack && !self.rmt_sack && self.sack_adv < SACK_ADVERTISE &&
+           (!self.snd_queue.is_empty() || !self.snd_buf.is_empty()) {
+            self.sack_adv += 1;
+            self.probe |= ASK_TELL;
+        }
+
         // flush window probing commands
         if (self.probe & ASK_SEND) != 0 {
             seg.cmd = CMD_WASK;
@@ -573,18 +1760,30 @@ impl KCP {
             seg.encode(&mut self.buffer);
         }
         self.probe = 0;
+        if let Some((sn, ts)) = self.pmtu_reply.take() {
+            seg.cmd = CMD_MTU_ACK;
+            seg.sn = sn;
+            seg.ts = ts;
+            let size = self.buffer.get_wpos();
+            if size as u32 + OVERHEAD > self.mtu {
+                if let Some(ref mut f) = self.on_update {
+                    f(&mut self.buffer, size);
+                }
+                self.buffer.clear();
+            }
+            seg.encode(&mut self.buffer);
+        }
+
+        self.expire_segments(current);
 
         // calculate window size
-        let mut cwnd = min(self.snd_wnd, self.rmt_wnd);
-        if self.nocwnd == 0 {
-            cwnd = min(self.cwnd, cwnd);
-        }
-        while let Some(mut seg) = self.snd_queue.pop_front() {
-            if self.snd_nxt >= self.snd_una + cwnd {
-                break;
-            }
+        let cwnd = self.cwnd_limit();
+        while self.snd_nxt < self.snd_una + cwnd {
+            let mut seg = match self.snd_queue.pop_front() {
+                Some(seg) => seg,
+                None => break,
+            };
             seg.conv = self.conv;
-            seg.cmd = CMD_PUSH;
             seg.ts = current;
             seg.sn = self.snd_nxt;
             seg.una = self.rcv_nxt;
@@ -592,7 +1791,8 @@ impl KCP {
             seg.rto = self.rx_rto;
             seg.fastack = 0;
             seg.xmit = 0;
-            self.snd_buf.push_back(seg);
+            self.snd_split = !ends_message(&seg);
+            self.snd_buf.insert(seg);
             self.snd_nxt += 1;
         }
 
@@ -607,11 +1807,25 @@ impl KCP {
         } else {
             self.rx_rto >> 3
         };
+        let rate = self.pace_rate();
+        self.refill_pacer(rate);
+        let mut throttled = false;
+        let mut sent = false;
 
         // flush data segments
         for segment in &mut self.snd_buf {
+            if rate > 0 && self.pace_credit <= 0 {
+                // paced out, the rest goes when check says so
+                break;
+            }
+            let need = OVERHEAD + segment.data.len() as u32;
+            if segment.is_due(current, resent, self.snd_queue.is_empty()) &&
+               !limits_allow(&self.rate_limit, &self.shared_limit, current, need) {
+                throttled = true;
+                break;
+            }
             let mut needsend = false;
-            if seg.xmit == 0 {
+            if segment.xmit == 0 {
                 needsend = true;
                 segment.xmit += 1;
                 segment.rto = self.rx_rto;
@@ -628,13 +1842,8 @@ impl KCP {
                 segment.rto = min(segment.rto, 8 * self.rx_rto);
                 segment.resendts = current + segment.rto;
                 lost = true;
-            } else if segment.fastack >= resent {
-                needsend = true;
-                segment.xmit += 1;
-                segment.fastack = 0;
-                segment.resendts = current + segment.rto;
-                change += 1;
-            } else if segment.fastack > 0 && self.snd_queue.is_empty() {
+            } else if segment.fastack >= resent ||
+                      (segment.fastack > 0 && self.snd_queue.is_empty()) {
                 needsend = true;
                 segment.xmit += 1;
                 segment.fastack = 0;
@@ -642,32 +1851,58 @@ impl KCP {
                 change += 1;
             }
             if needsend {
+                sent = true;
                 segment.ts = current;
                 segment.wnd = seg.wnd;
                 segment.una = self.rcv_nxt;
-                let size = self.buffer.get_wpos();
-                let need = OVERHEAD + segment.data.len() as u32;
-
-                if size as u32 + need >= self.mtu {
-                    if let Some(ref mut f) = self.on_update {
-                        f(&mut self.buffer, size);
+                if need > self.mtu {
+                    encode_pieces(segment, &mut self.buffer, self.mtu, &mut self.on_update);
+                } else {
+                    let size = self.buffer.get_wpos();
+                    if size > 0 && size as u32 + need > self.mtu {
+                        if let Some(ref mut f) = self.on_update {
+                            f(&mut self.buffer, size);
+                        }
+                        self.buffer.clear();
                     }
-                    self.buffer.clear();
+                    segment.encode(&mut self.buffer);
+                    self.buffer.write_bytes(&segment.data).unwrap();
+                }
+                if rate > 0 {
+                    self.pace_credit -= need as i32;
+                }
+                for limiter in self.rate_limit.iter().chain(self.shared_limit.iter()) {
+                    limiter.take(current, need);
                 }
-                segment.encode(&mut self.buffer);
-                self.buffer.write_bytes(&segment.data_bytes()).unwrap();
                 if segment.xmit >= self.dead_link {
                     self.state = u32::MAX;
                 }
             }
         }
+        if !throttled {
+            throttled = !self.flush_unreliable(&mut seg, rate, cwnd);
+        }
+        if piggyback && !sent {
+            // held back after all, the acks cannot wait for it
+            let mut ack = self.ack_segment();
+            self.flush_acks(&mut ack, false);
+        }
+        self.acklist.truncate(0);
         // flash remain segments
-        let size = self.buffer.get_wpos();
-        if size > 0 {
-            if let Some(ref mut f) = self.on_update {
-                f(&mut self.buffer, size);
-            }
+        self.flush_buffer();
+        if throttled {
+            self.ts_throttle = self.ts_throttle.or(Some(current));
+        } else if let Some(ts) = self.ts_throttle.take() {
+            self.throttled += current.wrapping_sub(ts);
+        }
+        if lost && self.pmtu_max != 0 && self.mtu > self.pmtu_min && self.pmtu_probe == 0 {
+            // maybe a black hole for packets this large now, check the MTU
+            // in use before blaming congestion alone
+            self.pmtu_probe = self.mtu;
+            self.pmtu_sent = 0;
+            self.ts_pmtu = current;
         }
+        self.flush_mtu_probe(&seg);
         // update ssthresh
         // rate halving, https://tools.ietf.org/html/rfc6937
         if change != 0 {
@@ -694,10 +1929,431 @@ impl KCP {
             self.cwnd = 1;
             self.incr = self.mss;
         }
+        // expire_segments may have dropped data
+        self.check_writable();
+        self.count_growth();
+    }
+
+    /// encode up to `cwnd` waiting datagrams within the pacing and rate
+    /// limits, returns false if the rate limiters held some back
+    fn flush_unreliable(&mut self, seg: &mut Segment, rate: u32, cwnd: u32) -> bool {
+        let current = self.current;
+        seg.cmd = CMD_UNRELIABLE;
+        seg.ts = current;
+        seg.sn = 0;
+        for _ in 0..cwnd {
+            if rate > 0 && self.pace_credit <= 0 {
+                break;
+            }
+            let need = match self.snd_unreliable.front() {
+                Some(data) => OVERHEAD + data.len() as u32,
+                None => break,
+            };
+            if !limits_allow(&self.rate_limit, &self.shared_limit, current, need) {
+                return false;
+            }
+            seg.data = Bytes::from(self.snd_unreliable.pop_front().unwrap());
+            let size = self.buffer.get_wpos();
+            if size > 0 && size as u32 + need > self.mtu {
+                if let Some(ref mut f) = self.on_update {
+                    f(&mut self.buffer, size);
+                }
+                self.buffer.clear();
+            }
+            seg.encode(&mut self.buffer);
+            self.buffer.write_bytes(&seg.data).unwrap();
+            if rate > 0 {
+                self.pace_credit -= need as i32;
+            }
+            for limiter in self.rate_limit.iter().chain(self.shared_limit.iter()) {
+                limiter.take(current, need);
+            }
+        }
+        seg.data = Bytes::new();
+        true
+    }
+
+    /// turn expired segments into CMD_SKIP: those with a sn are still
+    /// sent until acked, as is the rest of a message partly in snd_buf,
+    /// while messages not sent at all are dropped
+    fn expire_segments(&mut self, current: u32) {
+        let expired = |seg: &Segment| match seg.expire {
+            Some(expire) => sub_u32(current, expire) >= 0,
+            None => false,
+        };
+        if !self.snd_buf.iter().chain(self.snd_queue.iter()).any(&expired) {
+            return;
+        }
+        for seg in &mut self.snd_buf {
+            if expired(seg) {
+                self.snd_bytes -= seg.data.len();
+                skip_segment(seg);
+                seg.resendts = current;
+                seg.fastack = 0;
+            }
+        }
+        // rotated in place to keep the room of snd_queue
+        let mut split = self.snd_split;
+        for _ in 0..self.snd_queue.len() {
+            let mut seg = self.snd_queue.pop_front().unwrap();
+            let last = ends_message(&seg);
+            if expired(&seg) {
+                self.snd_bytes -= seg.data.len();
+                if split {
+                    skip_segment(&mut seg);
+                    self.snd_queue.push_back(seg);
+                }
+            } else {
+                self.snd_queue.push_back(seg);
+            }
+            split = split && !last;
+        }
+    }
+
+    /// send the next MTU probe in a packet of its own if one is due
+    fn flush_mtu_probe(&mut self, template: &Segment) {
+        if self.pmtu_max == 0 || sub_u32(self.current, self.ts_pmtu) < 0 {
+            return;
+        }
+        if self.pmtu_probe != 0 && self.pmtu_sent >= PMTU_PROBES {
+            // lost every time, the path does not carry packets this large
+            self.pmtu_high = self.pmtu_probe - 1;
+            if self.pmtu_probe <= self.mtu {
+                // not even the MTU in use, search again from the minimum
+                self.mtu = self.pmtu_min;
+                self.mss = self.mtu - OVERHEAD;
+                self.resegment();
+            }
+            self.pmtu_probe = 0;
+        }
+        if self.pmtu_probe == 0 {
+            if self.mtu >= self.pmtu_max {
+                return;
+            }
+            if self.pmtu_high <= self.mtu {
+                // settled, look for a larger MTU again later
+                self.pmtu_high = self.pmtu_max;
+                self.ts_pmtu = self.current + PMTU_RAISE;
+                return;
+            }
+            // try the largest size first, then search between
+            self.pmtu_probe = if self.pmtu_high == self.pmtu_max {
+                self.pmtu_max
+            } else {
+                (self.mtu + self.pmtu_high).div_ceil(2)
+            };
+            self.pmtu_sent = 0;
+        }
+        let padding = (self.pmtu_probe - OVERHEAD) as usize;
+        self.reset_scratch(padding);
+        let mut seg = Segment::new();
+        seg.conv = self.conv;
+        seg.cmd = CMD_MTU_PROBE;
+        seg.frg = template.frg;
+        seg.wnd = template.wnd;
+        seg.ts = self.current;
+        seg.sn = self.pmtu_probe;
+        seg.una = self.rcv_nxt;
+        seg.encode_len(&mut self.buffer, padding);
+        self.buffer.write_bytes(&self.scratch).unwrap();
+        self.flush_buffer();
+        self.pmtu_sent += 1;
+        self.ts_pmtu = self.current + 2 * self.rx_rto;
+    }
+
+    /// a probe of `size` bytes got through, use it if larger than the MTU
+    fn parse_mtu_ack(&mut self, size: u32) {
+        if self.pmtu_max == 0 || size > self.pmtu_max {
+            return;
+        }
+        if size <= self.mtu {
+            if size == self.pmtu_probe {
+                // the MTU in use still works, wait before searching again
+                self.pmtu_probe = 0;
+                self.pmtu_high = self.mtu;
+                self.ts_pmtu = self.current;
+            }
+            return;
+        }
+        self.mtu = size;
+        self.mss = size - OVERHEAD;
+        self.pmtu_high = max(self.pmtu_high, size);
+        if size >= self.pmtu_probe {
+            self.pmtu_probe = 0;
+            self.ts_pmtu = self.current;
+        }
+        self.resegment();
+    }
+
+    /// add a CMD_PIECE part to the segment sn, returns the whole payload
+    /// once its last part arrived. Parts are only taken in order, the
+    /// first one starts over
+    fn parse_piece(&mut self, sn: u32, offset: usize, total: usize, part: &[u8]) -> Option<Vec<u8>> {
+        if offset == 0 {
+            self.rcv_piece = Some((sn, total, Vec::with_capacity(total)));
+        }
+        let done = match self.rcv_piece {
+            Some((s, t, ref mut data)) if s == sn && t == total && data.len() == offset &&
+                                          offset + part.len() <= total => {
+                data.extend_from_slice(part);
+                data.len() == total
+            }
+            _ => return None,
+        };
+        if !done {
+            return None;
+        }
+        self.rcv_piece.take().map(|(_, _, data)| data)
+    }
+
+    /// the template of ack and window probing segments
+    fn ack_segment(&mut self) -> Segment {
+        let mut seg = Segment::new();
+        seg.conv = self.conv;
+        seg.cmd = CMD_ACK;
+        seg.wnd = self.wnd_unused() as u32;
+        seg.una = self.rcv_nxt;
+        if self.sack {
+            seg.frg = FRG_SACK;
+        }
+        seg
+    }
+
+    /// encode the acks in acklist, if `piggyback` is set those covered by
+    /// una are left in acklist instead
+    fn flush_acks(&mut self, seg: &mut Segment, piggyback: bool) {
+        if self.sack && self.rmt_sack && !self.acklist.is_empty() {
+            self.flush_sack(seg);
+            self.acklist.truncate(0);
+        }
+        let mut kept = 0;
+        for i in 0..self.acklist.len() / 2 {
+            let pair = self.ack_get(i);
+            if piggyback && pair.0 < self.rcv_nxt {
+                self.acklist[kept * 2] = pair.0;
+                self.acklist[kept * 2 + 1] = pair.1;
+                kept += 1;
+                continue;
+            }
+            let size = self.buffer.get_wpos();
+            if size as u32 + OVERHEAD > self.mtu {
+                if let Some(ref mut f) = self.on_update {
+                    f(&mut self.buffer, size);
+                }
+                self.buffer.clear();
+            }
+            seg.sn = pair.0;
+            seg.ts = pair.1;
+            seg.encode(&mut self.buffer);
+        }
+        self.acklist.truncate(kept * 2);
+    }
+
+    /// send only the pending acks, used by AckPolicy::NoDelay and Delayed
+    fn flush_ack(&mut self) {
+        if self.updated == 0 {
+            return;
+        }
+        let mut seg = self.ack_segment();
+        self.flush_acks(&mut seg, false);
+        self.flush_buffer();
+    }
+
+    /// hand whatever is left in buffer to the output
+    fn flush_buffer(&mut self) {
+        let size = self.buffer.get_wpos();
+        if size > 0 {
+            if let Some(ref mut f) = self.on_update {
+                f(&mut self.buffer, size);
+            }
+            self.buffer.clear();
+        }
+    }
+
+    /// the number of segments allowed in flight
+    fn cwnd_limit(&self) -> u32 {
+        let cwnd = min(self.snd_wnd, self.rmt_wnd);
+        if self.nocwnd == 0 {
+            min(self.cwnd, cwnd)
+        } else {
+            cwnd
+        }
+    }
+
+    /// the pacing rate in bytes per second, 0 if not paced
+    fn pace_rate(&self) -> u32 {
+        match self.pacing {
+            Pacing::Off => 0,
+            Pacing::Rate(rate) => rate,
+            Pacing::Window if self.rx_srtt == 0 => 0,
+            Pacing::Window => {
+                let window = self.cwnd_limit() as u64 * self.mtu as u64 * 1000;
+                min(window * 5 / 4 / self.rx_srtt as u64, u32::MAX as u64) as u32
+            }
+        }
+    }
+
+    /// add the credit earned since the last refill, keeping at most one
+    /// millisec of data or two packets so bursts stay short
+    fn refill_pacer(&mut self, rate: u32) {
+        let elapsed = max(sub_u32(self.current, self.ts_pace), 0) as u64;
+        self.ts_pace = self.current;
+        if rate == 0 {
+            return;
+        }
+        let burst = max(rate / 1000, 2 * self.mtu) as i64;
+        let credit = self.pace_credit as i64 + rate as i64 * elapsed as i64 / 1000;
+        self.pace_credit = min(credit, burst) as i32;
+    }
+
+    /// millisec until the pacer and the rate limiters let the next data
+    /// segment out, None if neither applies or nothing is waiting
+    fn send_wait(&self, current: u32) -> Option<u32> {
+        let rate = self.pace_rate();
+        let limited = self.rate_limit.is_some() || self.shared_limit.is_some();
+        if (rate == 0 && !limited) || self.updated == 0 {
+            return None;
+        }
+        let size = self.next_data_size(current)?;
+        let mut wait = 0;
+        if rate > 0 {
+            let elapsed = max(sub_u32(current, self.ts_pace), 0) as i64;
+            let missing = 1 - self.pace_credit as i64 - rate as i64 * elapsed / 1000;
+            if missing > 0 {
+                wait = ((missing * 1000 + rate as i64 - 1) / rate as i64) as u32;
+            }
+        }
+        for limiter in self.rate_limit.iter().chain(self.shared_limit.iter()) {
+            wait = max(wait, limiter.wait(current, size));
+        }
+        Some(wait)
+    }
+
+    /// whether a flush at `current` transmits at least one data segment
+    fn has_data_to_flush(&self, current: u32) -> bool {
+        self.next_data_size(current).is_some()
+    }
+
+    /// the packet size of the first data segment a flush at `current`
+    /// transmits, if any
+    fn next_data_size(&self, current: u32) -> Option<u32> {
+        let resent = if self.fastresend <= 0 {
+            u32::MAX
+        } else {
+            self.fastresend as u32
+        };
+        let drained = self.snd_queue.is_empty();
+        if let Some(seg) = self.snd_buf.iter().find(|seg| seg.is_due(current, resent, drained)) {
+            return Some(OVERHEAD + seg.data.len() as u32);
+        }
+        match self.snd_queue.front() {
+            Some(seg) if self.snd_nxt < self.snd_una + self.cwnd_limit() => {
+                Some(OVERHEAD + seg.data.len() as u32)
+            }
+            _ => None,
+        }
+    }
+
+    /// encode one CMD_SACK: sn is rcv_nxt, ts echoes the newest acked
+    /// segment and the payload has bit i set if sn + i is in rcv_buf
+    fn flush_sack(&mut self, seg: &mut Segment) {
+        let limit = (self.mss * 8) as usize;
+        let bits = self.rcv_buf
+            .iter()
+            .map(|s| s.sn.wrapping_sub(self.rcv_nxt) as usize)
+            .take_while(|&offset| offset < limit)
+            .last()
+            .map_or(0, |offset| offset + 1);
+        self.reset_scratch(bits.div_ceil(8));
+        for s in &self.rcv_buf {
+            let offset = s.sn.wrapping_sub(self.rcv_nxt) as usize;
+            if offset >= limit {
+                break;
+            }
+            self.scratch[offset / 8] |= 1 << (offset % 8);
+        }
+        seg.cmd = CMD_SACK;
+        seg.sn = self.rcv_nxt;
+        seg.ts = self.ack_get(self.acklist.len() / 2 - 1).1;
+        let size = self.buffer.get_wpos();
+        if size as u32 + OVERHEAD + self.scratch.len() as u32 > self.mtu {
+            if let Some(ref mut f) = self.on_update {
+                f(&mut self.buffer, size);
+            }
+            self.buffer.clear();
+        }
+        seg.encode_len(&mut self.buffer, self.scratch.len());
+        self.buffer.write_bytes(&self.scratch).unwrap();
+        seg.cmd = CMD_ACK;
+    }
+
+    /// clear scratch and fill it with `len` zeros, counting it if it has
+    /// to grow
+    fn reset_scratch(&mut self, len: usize) {
+        if self.scratch.capacity() < len {
+            self.allocs.buffers += 1;
+        }
+        self.scratch.clear();
+        self.scratch.resize(len, 0);
+    }
+
+    /// count in allocs if a container of segments grew since last time
+    fn count_growth(&mut self) {
+        let capacity = self.snd_queue.capacity() + self.rcv_queue.capacity() +
+                       self.snd_buf.capacity() + self.rcv_buf.capacity() +
+                       self.acklist.capacity();
+        if capacity > self.capacity {
+            self.allocs.segments += 1;
+            self.capacity = capacity;
+        }
+    }
+
+    /// a copy of the payload of a received segment, in a buffer of the
+    /// pool if there is one
+    fn payload(&mut self, data: &[u8]) -> Bytes {
+        if self.pool.is_some() {
+            return self.pooled(data);
+        }
+        self.allocs.payloads += 1;
+        Bytes::copy_from_slice(data)
+    }
+
+    /// a copy of `data` in a buffer of the pool, a new one if none fits
+    fn pooled(&mut self, data: &[u8]) -> Bytes {
+        let mut buf = match self.pool.as_ref().and_then(|pool| pool.take()) {
+            Some(buf) if buf.capacity() >= data.len() => {
+                self.allocs.reused += 1;
+                buf
+            }
+            _ => {
+                self.allocs.payloads += 1;
+                // shared from the start, so freezing it and taking it back
+                // never allocates again
+                BytesMut::with_capacity(max(data.len(), self.mss as usize)).split_off(0)
+            }
+        };
+        buf.extend_from_slice(data);
+        buf.freeze()
+    }
+
+    /// give the buffer of a payload no longer needed back to the pool
+    fn recycle(&mut self, data: Bytes) {
+        if let Some(ref pool) = self.pool {
+            pool.put(data);
+        }
+    }
+
+    /// an output buffer large enough for `mtu`, kept if it already is
+    fn reserve_buffer(&mut self, mtu: u32) {
+        let size = ((mtu + OVERHEAD) * 3) as usize;
+        if self.allocs.buffers == 0 || self.buffer.len() < size {
+            self.buffer = ByteBuffer::with_capacity(size);
+            self.allocs.buffers += 1;
+        }
     }
 
     fn shrink_buf(&mut self) {
-        if let Some(seg) = self.snd_buf.get(0) {
+        if let Some(seg) = self.snd_buf.front() {
             self.snd_una = seg.sn;
         } else {
             self.snd_una = self.snd_nxt;
@@ -713,11 +2369,122 @@ impl KCP {
                               min(self.rx_srtt, rtt)) / 4;
             self.rx_srtt = max((self.rx_srtt * 7 + rtt) / 8, 1);
         }
-        self.rx_rto = min(max(self.rx_minrto, (self.rx_srtt + max(1, self.rx_rttval * 4))),
+        self.rx_rto = min(max(self.rx_minrto, self.rx_srtt + max(1, self.rx_rttval * 4)),
                           RTO_MAX);
     }
 }
 
+/// read the conv from the header of a raw KCP packet
+pub fn get_conv(packet: &[u8]) -> Option<u32> {
+    if packet.len() < 4 {
+        return None;
+    }
+    Some(Reader { data: packet }.u32())
+}
+
+/// whether `packet` may open a new session: it starts with a well formed
+/// data segment from a peer that has not received anything yet, as the
+/// first packet of every session does
+pub fn opens_session(packet: &[u8]) -> bool {
+    if packet.len() < OVERHEAD as usize {
+        return false;
+    }
+    let mut data = Reader { data: packet };
+    data.bytes(4);
+    let cmd = data.u8();
+    data.bytes(11);
+    let una = data.u32();
+    let length = data.u32();
+    (cmd == CMD_PUSH || cmd == CMD_PUSH_CONT || cmd == CMD_PUSH_HEAD) && una == 0 &&
+    length as usize <= data.remain()
+}
+
+/// whether a pushed segment is the last fragment of its message
+fn ends_message(seg: &Segment) -> bool {
+    (seg.cmd == CMD_PUSH || seg.cmd == CMD_PUSH_HEAD || seg.cmd == CMD_SKIP) && seg.frg == 0
+}
+
+/// encode `seg` as CMD_PIECE parts that fit in packets of `mtu` bytes
+fn encode_pieces(seg: &Segment,
+                 buffer: &mut ByteBuffer,
+                 mtu: u32,
+                 output: &mut Option<OutputFn>) {
+    let room = (mtu - OVERHEAD - PIECE_OVERHEAD) as usize;
+    let mut offset = 0;
+    while offset < seg.data.len() {
+        let end = min(seg.data.len(), offset + room);
+        let size = buffer.get_wpos();
+        if size > 0 && (size + OVERHEAD as usize + PIECE_OVERHEAD as usize + end - offset) as u32 > mtu {
+            if let Some(ref mut f) = *output {
+                f(buffer, size);
+            }
+            buffer.clear();
+        }
+        buffer.write_u32(seg.conv).unwrap();
+        buffer.write_u8(CMD_PIECE as u8).unwrap();
+        buffer.write_u8(seg.frg as u8).unwrap();
+        buffer.write_u16(seg.wnd as u16).unwrap();
+        buffer.write_u32(seg.ts).unwrap();
+        buffer.write_u32(seg.sn).unwrap();
+        buffer.write_u32(seg.una).unwrap();
+        buffer.write_u32((PIECE_OVERHEAD as usize + end - offset) as u32).unwrap();
+        buffer.write_u8(seg.cmd as u8).unwrap();
+        buffer.write_u32(offset as u32).unwrap();
+        buffer.write_u32(seg.data.len() as u32).unwrap();
+        buffer.write_bytes(&seg.data[offset..end]).unwrap();
+        offset = end;
+    }
+}
+
+/// replace the data of an expired segment by a CMD_SKIP header
+fn skip_segment(seg: &mut Segment) {
+    seg.frg = if ends_message(seg) { 0 } else { 1 };
+    seg.cmd = CMD_SKIP;
+    seg.data = Bytes::new();
+    seg.expire = None;
+}
+
+/// whether every rate limiter has tokens for `bytes`
+fn limits_allow(own: &Option<RateLimiter>,
+                shared: &Option<RateLimiter>,
+                current: u32,
+                bytes: u32)
+                -> bool {
+    own.iter().chain(shared.iter()).all(|limiter| limiter.allow(current, bytes))
+}
+
 fn sub_u32(a: u32, b: u32) -> i32 {
     a.wrapping_sub(b) as i32
 }
+
+/// takes little endian integers and slices off the front of a packet,
+/// the caller checks remain first
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn remain(&self) -> usize {
+        self.data.len()
+    }
+
+    fn bytes(&mut self, n: usize) -> &'a [u8] {
+        let (bytes, rest) = self.data.split_at(n);
+        self.data = rest;
+        bytes
+    }
+
+    fn u8(&mut self) -> u32 {
+        self.bytes(1)[0] as u32
+    }
+
+    fn u16(&mut self) -> u32 {
+        let b = self.bytes(2);
+        b[0] as u32 | (b[1] as u32) << 8
+    }
+
+    fn u32(&mut self) -> u32 {
+        let b = self.bytes(4);
+        b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+    }
+}
@@ -1,4 +1,49 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+#[cfg(feature = "std")]
+extern crate core;
+extern crate alloc;
+extern crate bytes;
+#[cfg(feature = "std")]
 extern crate fixbuf;
+#[cfg(feature = "std")]
+extern crate rand;
+#[cfg(feature = "std")]
+extern crate time;
+#[cfg(feature = "async")]
+extern crate futures_io;
+#[cfg(feature = "reactor")]
+extern crate mio;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(feature = "std", target_os = "linux"))]
+extern crate libc;
 pub mod kcp;
-pub use kcp::KCP;
+pub mod limiter;
+pub mod pool;
+#[cfg(feature = "std")]
+pub mod batch;
+#[cfg(feature = "std")]
+pub mod capi;
+#[cfg(feature = "reactor")]
+pub mod reactor;
+pub mod scheduler;
+#[cfg(feature = "std")]
+pub mod smux;
+#[cfg(feature = "std")]
+pub mod socks5;
+#[cfg(feature = "std")]
+pub mod tunnel;
+pub use bytes::Bytes;
+pub use kcp::{get_conv, opens_session, AckPolicy, AllocStats, KcpSnapshot, Pacing, ERR_WOULD_BLOCK, KCP,
+              SNAPSHOT_VERSION};
+pub use limiter::RateLimiter;
+pub use pool::BufferPool;
+pub use scheduler::UpdateScheduler;
+#[cfg(feature = "std")]
+pub use fixbuf::ByteBuffer;
+#[cfg(not(feature = "std"))]
+pub use buffer::ByteBuffer;
+#[cfg(not(feature = "std"))]
+mod buffer;
+mod ring;
 mod segment;
@@ -0,0 +1,155 @@
+//! Token bucket bandwidth limiter for `KCP::flush`.
+//!
+//! A `RateLimiter` can be given to one session or cloned into many, the
+//! clones share one bucket. Time is the `current` millisec passed to
+//! `KCP::update`, so sessions sharing a limiter must use the same clock.
+//! Without the `std` feature the clones can only be used on one thread.
+use core::cmp::min;
+use core::fmt;
+#[cfg(not(feature = "std"))]
+use alloc::rc::Rc;
+#[cfg(not(feature = "std"))]
+use core::cell::{RefCell, RefMut};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex, MutexGuard};
+
+struct Bucket {
+    /// bytes per second
+    rate: u32,
+    /// the most bytes that can be sent at once
+    burst: u32,
+    /// available bytes times 1000, so no fraction is lost on refill
+    tokens: i64,
+    /// when tokens was last refilled, None before the first use
+    ts: Option<u32>,
+    /// since when a request is held back
+    blocked_since: Option<u32>,
+    /// total millisec requests were held back
+    throttled: u32,
+}
+
+impl Bucket {
+    fn refill(&mut self, current: u32) {
+        let full = self.burst as i64 * 1000;
+        let elapsed = match self.ts {
+            Some(ts) => current.wrapping_sub(ts) as i32,
+            None => {
+                self.tokens = full;
+                0
+            }
+        };
+        self.ts = Some(current);
+        if elapsed > 0 {
+            self.tokens = min(self.tokens + elapsed as i64 * self.rate as i64, full);
+        }
+    }
+
+    /// the tokens needed to send `bytes`, a packet larger than the burst
+    /// goes once the bucket is full
+    fn need(&self, bytes: u32) -> i64 {
+        min(bytes, self.burst) as i64 * 1000
+    }
+}
+
+#[cfg(feature = "std")]
+type Shared = Arc<Mutex<Bucket>>;
+#[cfg(not(feature = "std"))]
+type Shared = Rc<RefCell<Bucket>>;
+
+/// a token bucket of `rate` bytes per second holding up to `burst` bytes
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Shared,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        let bucket = Bucket {
+            rate,
+            burst,
+            tokens: 0,
+            ts: None,
+            blocked_since: None,
+            throttled: 0,
+        };
+        RateLimiter { bucket: Shared::new(bucket.into()) }
+    }
+
+    #[cfg(feature = "std")]
+    fn bucket(&self) -> MutexGuard<'_, Bucket> {
+        self.bucket.lock().unwrap()
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn bucket(&self) -> RefMut<'_, Bucket> {
+        self.bucket.borrow_mut()
+    }
+
+    /// change the rate and burst of every session sharing this limiter
+    pub fn set_rate(&self, rate: u32, burst: u32) {
+        let mut bucket = self.bucket();
+        bucket.rate = rate;
+        bucket.burst = burst;
+        bucket.tokens = min(bucket.tokens, burst as i64 * 1000);
+    }
+
+    /// bytes per second and burst size
+    pub fn rate(&self) -> (u32, u32) {
+        let bucket = self.bucket();
+        (bucket.rate, bucket.burst)
+    }
+
+    /// total millisec some session had data held back by this limiter,
+    /// up to the last time it was used
+    pub fn throttled_time(&self) -> u32 {
+        let bucket = self.bucket();
+        match (bucket.blocked_since, bucket.ts) {
+            (Some(since), Some(ts)) => bucket.throttled + ts.wrapping_sub(since),
+            _ => bucket.throttled,
+        }
+    }
+
+    /// millisec until `bytes` can be sent, 0 if they can be sent now
+    pub fn wait(&self, current: u32, bytes: u32) -> u32 {
+        let mut bucket = self.bucket();
+        bucket.refill(current);
+        let missing = bucket.need(bytes) - bucket.tokens;
+        if missing <= 0 {
+            return 0;
+        }
+        if bucket.rate == 0 {
+            return u32::MAX;
+        }
+        ((missing + bucket.rate as i64 - 1) / bucket.rate as i64) as u32
+    }
+
+    /// whether `bytes` can be sent now, marks the limiter throttled if not
+    pub fn allow(&self, current: u32, bytes: u32) -> bool {
+        let mut bucket = self.bucket();
+        bucket.refill(current);
+        if bucket.tokens >= bucket.need(bytes) {
+            return true;
+        }
+        if bucket.blocked_since.is_none() {
+            bucket.blocked_since = Some(current);
+        }
+        false
+    }
+
+    /// take the tokens of `bytes` that were sent
+    pub fn take(&self, current: u32, bytes: u32) {
+        let mut bucket = self.bucket();
+        bucket.refill(current);
+        bucket.tokens -= bytes as i64 * 1000;
+        if let Some(since) = bucket.blocked_since.take() {
+            bucket.throttled += current.wrapping_sub(since);
+        }
+    }
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (rate, burst) = self.rate();
+        write!(f, "RateLimiter {{ rate: {}, burst: {} }}", rate, burst)
+    }
+}
@@ -0,0 +1,87 @@
+//! Recycled payload buffers for `KCP`, see `KCP::set_buffer_pool`.
+//!
+//! A `BufferPool` can be given to one session or cloned into many, the
+//! clones share the free buffers. A session with a pool copies sent
+//! messages and received segments into buffers from the pool and puts
+//! them back once they are acked or received, so in steady state it stops
+//! allocating, which `KCP::alloc_stats` shows. Without the `std` feature
+//! the clones can only be used on one thread.
+use alloc::vec::Vec;
+use bytes::{Bytes, BytesMut};
+use core::fmt;
+#[cfg(not(feature = "std"))]
+use alloc::rc::Rc;
+#[cfg(not(feature = "std"))]
+use core::cell::{RefCell, RefMut};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex, MutexGuard};
+
+struct Buffers {
+    free: Vec<BytesMut>,
+    /// the most free buffers kept
+    max: usize,
+}
+
+#[cfg(feature = "std")]
+type Shared = Arc<Mutex<Buffers>>;
+#[cfg(not(feature = "std"))]
+type Shared = Rc<RefCell<Buffers>>;
+
+/// free payload buffers, at most `max` of them
+#[derive(Clone)]
+pub struct BufferPool {
+    buffers: Shared,
+}
+
+impl BufferPool {
+    pub fn new(max: usize) -> Self {
+        let buffers = Buffers {
+            free: Vec::new(),
+            max,
+        };
+        BufferPool { buffers: Shared::new(buffers.into()) }
+    }
+
+    #[cfg(feature = "std")]
+    fn buffers(&self) -> MutexGuard<'_, Buffers> {
+        self.buffers.lock().unwrap()
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn buffers(&self) -> RefMut<'_, Buffers> {
+        self.buffers.borrow_mut()
+    }
+
+    /// the number of free buffers
+    pub fn len(&self) -> usize {
+        self.buffers().free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// an empty buffer, None if none is free
+    pub fn take(&self) -> Option<BytesMut> {
+        self.buffers().free.pop()
+    }
+
+    /// keep the buffer of `data` for reuse, unless something else still
+    /// refers to it or the pool is full
+    pub fn put(&self, data: Bytes) {
+        let mut buffers = self.buffers();
+        if buffers.free.len() >= buffers.max {
+            return;
+        }
+        if let Ok(mut buf) = data.try_into_mut() {
+            buf.clear();
+            buffers.free.push(buf);
+        }
+    }
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BufferPool {{ free: {} }}", self.len())
+    }
+}
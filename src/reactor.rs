@@ -0,0 +1,330 @@
+//! Many KCP sessions over one mio `UdpSocket`, without an async runtime.
+//!
+//! `KcpReactor` plugs into an existing mio event loop: register it with the
+//! loop's `Registry`, poll with the timeout it asks for and call
+//! `KcpReactor::poll` whenever its token is ready or the timeout elapsed.
+//! Each poll reads every waiting datagram, hands it to its session, updates
+//! the sessions that are due according to `KCP::check` and sends what they
+//! produced in one batch, see `batch::SendBatch`. Idle sessions are left
+//! alone until they get a datagram or are sent to, see `UpdateScheduler`.
+//! What happened to each session is reported as `SessionEvent`s.
+//!
+//! Sessions are found by peer address and conv. A reactor accepting
+//! sessions creates one for an unknown pair whose datagram opens a session
+//! (see `opens_session`), as a server would, up to a maximum number of
+//! sessions. Datagrams the socket cannot take right away are dropped like
+//! any lost datagram and retransmitted by KCP.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::net::{self, SocketAddr};
+use std::rc::Rc;
+use std::time::Duration;
+use fixbuf::ByteBuffer;
+use mio::{Interest, Registry, Token};
+use mio::net::UdpSocket;
+use batch::SendBatch;
+use kcp::{get_conv, opens_session, KCP};
+use scheduler::UpdateScheduler;
+
+/// the largest datagram read from the socket
+const BUF_SIZE: usize = 65536;
+
+/// a session of a reactor, valid until the session is closed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SessionId(pub usize);
+
+/// what a poll found out about a session
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// a peer started a new session
+    Accepted(SessionId),
+    /// a message can be taken with recv
+    Readable(SessionId),
+    /// send can be used again after it returned ERR_WOULD_BLOCK
+    Writable(SessionId),
+    /// the peer stopped acking, see KCP::is_dead_link; close the session
+    Closed(SessionId),
+}
+
+/// datagrams produced by the sessions, waiting for the socket
+type Outbox = Rc<RefCell<SendBatch>>;
+
+/// applied to every new session
+type Setup = Box<dyn FnMut(&mut KCP)>;
+
+struct Entry {
+    kcp: KCP,
+    key: (SocketAddr, u32),
+    /// Closed was reported
+    closed: bool,
+}
+
+pub struct KcpReactor {
+    socket: UdpSocket,
+    sessions: Vec<Option<Entry>>,
+    /// free slots of sessions
+    free: Vec<usize>,
+    by_key: HashMap<(SocketAddr, u32), SessionId>,
+    /// when the sessions are due, by slot
+    timers: UpdateScheduler,
+    accept: bool,
+    max_sessions: usize,
+    /// applied to every new session
+    setup: Option<Setup>,
+    outbox: Outbox,
+    /// a std handle on the socket for the batched sends, made by the
+    /// first poll
+    sender: Option<net::UdpSocket>,
+    writable: Rc<RefCell<Vec<SessionId>>>,
+    /// the slots due in a poll
+    due: Vec<usize>,
+    buf: Vec<u8>,
+}
+
+impl KcpReactor {
+    pub fn new(socket: UdpSocket) -> Self {
+        KcpReactor {
+            socket,
+            sessions: Vec::new(),
+            free: Vec::new(),
+            by_key: HashMap::new(),
+            timers: UpdateScheduler::new(),
+            accept: false,
+            max_sessions: 1024,
+            setup: None,
+            outbox: Rc::new(RefCell::new(SendBatch::new())),
+            sender: None,
+            writable: Rc::new(RefCell::new(Vec::new())),
+            due: Vec::new(),
+            buf: vec![0; BUF_SIZE],
+        }
+    }
+
+    /// watch the socket for readable datagrams under `token`
+    pub fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        registry.register(&mut self.socket, token, Interest::READABLE)
+    }
+
+    pub fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        registry.deregister(&mut self.socket)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// create a session for a datagram of an unknown peer and conv that
+    /// opens one
+    pub fn set_accept(&mut self, accept: bool) {
+        self.accept = accept;
+    }
+
+    /// accept no more sessions while `max` exist, 1024 by default
+    pub fn set_max_sessions(&mut self, max: usize) {
+        self.max_sessions = max;
+    }
+
+    /// configure every new session, e.g. with no_delay and wnd_size
+    pub fn set_session_setup<F>(&mut self, f: F)
+        where F: FnMut(&mut KCP),
+              F: 'static
+    {
+        self.setup = Some(Box::new(f));
+    }
+
+    /// start a session with `addr`
+    pub fn connect(&mut self, addr: SocketAddr, conv: u32) -> SessionId {
+        if let Some(&id) = self.by_key.get(&(addr, conv)) {
+            return id;
+        }
+        self.insert((addr, conv))
+    }
+
+    fn insert(&mut self, key: (SocketAddr, u32)) -> SessionId {
+        let id = match self.free.pop() {
+            Some(slot) => SessionId(slot),
+            None => {
+                self.sessions.push(None);
+                SessionId(self.sessions.len() - 1)
+            }
+        };
+        let (addr, conv) = key;
+        let outbox = self.outbox.clone();
+        let mut kcp = KCP::new(conv, move |buf: &mut ByteBuffer, size: usize| {
+            // ByteBuffer only hands out copies, take one and keep it
+            let mut packet = buf.to_bytes();
+            packet.truncate(size);
+            outbox.borrow_mut().push(packet, Some(addr));
+        });
+        let writable = self.writable.clone();
+        kcp.set_writable_callback(move || writable.borrow_mut().push(id));
+        if let Some(ref mut setup) = self.setup {
+            setup(&mut kcp);
+        }
+        self.sessions[id.0] = Some(Entry {
+            kcp,
+            key,
+            closed: false,
+        });
+        self.by_key.insert(key, id);
+        self.timers.schedule_now(id.0);
+        id
+    }
+
+    /// the session's KCP, to change its options or look at its state; it
+    /// is updated by the next poll
+    pub fn session(&mut self, id: SessionId) -> Option<&mut KCP> {
+        match self.sessions.get_mut(id.0) {
+            Some(Some(entry)) => {
+                self.timers.schedule_now(id.0);
+                Some(&mut entry.kcp)
+            }
+            _ => None,
+        }
+    }
+
+    /// the peer address and conv of a session
+    pub fn peer(&self, id: SessionId) -> Option<(SocketAddr, u32)> {
+        match self.sessions.get(id.0) {
+            Some(Some(entry)) => Some(entry.key),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_key.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_key.is_empty()
+    }
+
+    /// KCP::send on a session, flushed by the next poll; -1 if the
+    /// session does not exist
+    pub fn send(&mut self, id: SessionId, data: &[u8]) -> isize {
+        match self.session(id) {
+            Some(kcp) => {
+                let mut buf = ByteBuffer::with_capacity(data.len());
+                buf.write_bytes(data).unwrap();
+                kcp.send(&mut buf)
+            }
+            None => -1,
+        }
+    }
+
+    /// KCP::recv on a session, -1 if the session does not exist or has no
+    /// message waiting. An idle session stays parked unless the recv
+    /// reopened its receive window, which the peer is told about
+    pub fn recv(&mut self, id: SessionId, buf: &mut ByteBuffer) -> isize {
+        match self.sessions.get_mut(id.0) {
+            Some(&mut Some(ref mut entry)) => {
+                let idle = entry.kcp.is_idle();
+                let n = entry.kcp.recv(buf);
+                if idle && !entry.kcp.is_idle() {
+                    self.timers.schedule_now(id.0);
+                }
+                n
+            }
+            _ => -1,
+        }
+    }
+
+    /// forget a session, its id may be reused
+    pub fn close(&mut self, id: SessionId) {
+        if let Some(entry) = self.sessions.get_mut(id.0).and_then(|slot| slot.take()) {
+            self.by_key.remove(&entry.key);
+            self.timers.cancel(id.0);
+            self.free.push(id.0);
+        }
+    }
+
+    /// how long the event loop may block before the next poll is due,
+    /// None if no session is scheduled
+    pub fn timeout(&self, current: u32) -> Option<Duration> {
+        self.timers.timeout(current).map(|wait| Duration::from_millis(wait as u64))
+    }
+
+    /// read every waiting datagram, update the sessions that are due and
+    /// send their output, appending what happened to `events`
+    pub fn poll(&mut self, current: u32, events: &mut Vec<SessionEvent>) -> io::Result<()> {
+        loop {
+            let (n, addr) = match self.socket.recv_from(&mut self.buf) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // e.g. an ICMP error of an earlier datagram
+                Err(_) => continue,
+            };
+            let conv = match get_conv(&self.buf[..n]) {
+                Some(conv) => conv,
+                None => continue,
+            };
+            let id = match self.by_key.get(&(addr, conv)) {
+                Some(&id) => id,
+                None if self.accept && self.len() < self.max_sessions &&
+                        opens_session(&self.buf[..n]) => {
+                    let id = self.insert((addr, conv));
+                    events.push(SessionEvent::Accepted(id));
+                    id
+                }
+                None => continue,
+            };
+            let readable = match self.sessions[id.0] {
+                Some(ref mut entry) => {
+                    let before = entry.kcp.peek_size() > 0;
+                    entry.kcp.input_slice(&self.buf[..n]);
+                    !before && entry.kcp.peek_size() > 0
+                }
+                None => false,
+            };
+            if readable {
+                events.push(SessionEvent::Readable(id));
+            }
+            // acks go out with the next update
+            self.timers.schedule_now(id.0);
+        }
+        self.timers.advance(current, &mut self.due);
+        for slot in self.due.drain(..) {
+            if let Some(&mut Some(ref mut entry)) = self.sessions.get_mut(slot) {
+                entry.kcp.update(current);
+                if entry.kcp.is_dead_link() && !entry.closed {
+                    entry.closed = true;
+                    events.push(SessionEvent::Closed(SessionId(slot)));
+                }
+                if !entry.kcp.is_idle() {
+                    self.timers.schedule(slot, entry.kcp.check(current));
+                }
+            }
+        }
+        for id in self.writable.borrow_mut().drain(..) {
+            events.push(SessionEvent::Writable(id));
+        }
+        self.send_output()
+    }
+
+    fn send_output(&mut self) -> io::Result<()> {
+        let mut outbox = self.outbox.borrow_mut();
+        if outbox.is_empty() {
+            return Ok(());
+        }
+        if self.sender.is_none() {
+            self.sender = Some(std_socket(&self.socket)?);
+        }
+        outbox.send(self.sender.as_ref().unwrap());
+        Ok(())
+    }
+}
+
+/// a std socket sharing the file description of `socket`, nonblocking too
+#[cfg(unix)]
+fn std_socket(socket: &UdpSocket) -> io::Result<net::UdpSocket> {
+    use std::os::unix::io::AsFd;
+    Ok(net::UdpSocket::from(socket.as_fd().try_clone_to_owned()?))
+}
+
+#[cfg(windows)]
+fn std_socket(socket: &UdpSocket) -> io::Result<net::UdpSocket> {
+    use std::os::windows::io::AsSocket;
+    Ok(net::UdpSocket::from(socket.as_socket().try_clone_to_owned()?))
+}
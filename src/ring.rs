@@ -0,0 +1,180 @@
+use alloc::vec::Vec;
+use core::{iter, mem, slice};
+use segment::Segment;
+
+/// segments kept by sn, as snd_buf and rcv_buf. Segment sn lives in slot
+/// `sn % capacity`, so finding, adding and removing one by sn is O(1);
+/// the capacity doubles when a sn does not fit beside the others
+#[derive(Clone, Debug, Default)]
+pub struct SegmentRing {
+    slots: Vec<Option<Segment>>,
+    /// the sn of the first segment and one past the last, if not empty
+    first: u32,
+    end: u32,
+    len: usize,
+}
+
+pub type Iter<'a> = iter::FilterMap<iter::Chain<slice::Iter<'a, Option<Segment>>,
+                                                slice::Iter<'a, Option<Segment>>>,
+                                    fn(&'a Option<Segment>) -> Option<&'a Segment>>;
+pub type IterMut<'a> = iter::FilterMap<iter::Chain<slice::IterMut<'a, Option<Segment>>,
+                                                   slice::IterMut<'a, Option<Segment>>>,
+                                       fn(&'a mut Option<Segment>) -> Option<&'a mut Segment>>;
+
+impl SegmentRing {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// the slots allocated, never given back
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn slot(&self, sn: u32) -> usize {
+        sn as usize & (self.slots.len() - 1)
+    }
+
+    /// whether sn lies between first and end
+    fn holds(&self, sn: u32) -> bool {
+        self.len > 0 && sn.wrapping_sub(self.first) < self.end.wrapping_sub(self.first)
+    }
+
+    pub fn get(&self, sn: u32) -> Option<&Segment> {
+        if !self.holds(sn) {
+            return None;
+        }
+        self.slots[self.slot(sn)].as_ref()
+    }
+
+    pub fn get_mut(&mut self, sn: u32) -> Option<&mut Segment> {
+        if !self.holds(sn) {
+            return None;
+        }
+        let slot = self.slot(sn);
+        self.slots[slot].as_mut()
+    }
+
+    pub fn contains(&self, sn: u32) -> bool {
+        self.get(sn).is_some()
+    }
+
+    /// the segment with the lowest sn
+    pub fn front(&self) -> Option<&Segment> {
+        self.get(self.first)
+    }
+
+    /// add a segment, false if one with its sn is already there
+    pub fn insert(&mut self, seg: Segment) -> bool {
+        let sn = seg.sn;
+        if self.len == 0 {
+            self.first = sn;
+            self.end = sn.wrapping_add(1);
+        } else if self.holds(sn) {
+            if self.contains(sn) {
+                return false;
+            }
+        } else if (sn.wrapping_sub(self.first) as i32) < 0 {
+            self.first = sn;
+        } else {
+            self.end = sn.wrapping_add(1);
+        }
+        let span = self.end.wrapping_sub(self.first) as usize;
+        if span > self.slots.len() {
+            self.grow(span);
+        }
+        let slot = self.slot(sn);
+        self.slots[slot] = Some(seg);
+        self.len += 1;
+        true
+    }
+
+    fn grow(&mut self, span: usize) {
+        let capacity = span.next_power_of_two().max(16);
+        let old = mem::replace(&mut self.slots, Vec::with_capacity(capacity));
+        self.slots.resize(capacity, None);
+        for seg in old.into_iter().flatten() {
+            let slot = self.slot(seg.sn);
+            self.slots[slot] = Some(seg);
+        }
+    }
+
+    pub fn remove(&mut self, sn: u32) -> Option<Segment> {
+        if !self.holds(sn) {
+            return None;
+        }
+        let slot = self.slot(sn);
+        let seg = self.slots[slot].take();
+        if seg.is_some() {
+            self.len -= 1;
+            if self.len == 0 {
+                self.end = self.first;
+            } else if sn == self.first {
+                while !self.contains(self.first) {
+                    self.first = self.first.wrapping_add(1);
+                }
+            } else if sn.wrapping_add(1) == self.end {
+                while !self.contains(self.end.wrapping_sub(1)) {
+                    self.end = self.end.wrapping_sub(1);
+                }
+            }
+        }
+        seg
+    }
+
+    pub fn pop_front(&mut self) -> Option<Segment> {
+        let first = self.first;
+        self.remove(first)
+    }
+
+    /// the slots from first to end, as the two parts around the wrap
+    fn bounds(&self) -> (usize, usize, usize) {
+        if self.len == 0 {
+            return (0, 0, 0);
+        }
+        let start = self.slot(self.first);
+        let span = self.end.wrapping_sub(self.first) as usize;
+        if start + span <= self.slots.len() {
+            (start, start + span, 0)
+        } else {
+            (start, self.slots.len(), start + span - self.slots.len())
+        }
+    }
+
+    /// the segments by sn
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        let (start, stop, wrapped) = self.bounds();
+        let (head, tail) = self.slots.split_at(start);
+        let some: fn(&'a Option<Segment>) -> Option<&'a Segment> = Option::as_ref;
+        tail[..stop - start].iter().chain(head[..wrapped].iter()).filter_map(some)
+    }
+
+    pub fn iter_mut<'a>(&'a mut self) -> IterMut<'a> {
+        let (start, stop, wrapped) = self.bounds();
+        let (head, tail) = self.slots.split_at_mut(start);
+        let some: fn(&'a mut Option<Segment>) -> Option<&'a mut Segment> = Option::as_mut;
+        tail[..stop - start].iter_mut().chain(head[..wrapped].iter_mut()).filter_map(some)
+    }
+}
+
+impl<'a> IntoIterator for &'a SegmentRing {
+    type Item = &'a Segment;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a mut SegmentRing {
+    type Item = &'a mut Segment;
+    type IntoIter = IterMut<'a>;
+
+    fn into_iter(self) -> IterMut<'a> {
+        self.iter_mut()
+    }
+}
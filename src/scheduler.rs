@@ -0,0 +1,157 @@
+//! Timer wheel telling which of many sessions `KCP::update` is due for.
+//!
+//! Sessions are identified by a `usize` handle, e.g. their index in a
+//! slab. After updating a session, schedule it at `KCP::check`, or leave
+//! it unscheduled while `KCP::is_idle`; after `input`, `send` or `recv`
+//! schedule it at the current time so it is updated on the next tick.
+//! `advance` then returns only the sessions that are due, so idle
+//! sessions cost nothing and busy ones are updated when they need to be.
+//!
+//! The wheel has one slot per millisec and keeps deadlines beyond one turn
+//! in their slot until the turn they are due. Rescheduling adds a new
+//! entry and leaves the old one to be dropped when its slot comes up.
+use alloc::vec::Vec;
+use core::cmp::{max, min};
+
+/// the slots of the wheel, one per millisec
+const SLOTS: usize = 1024;
+
+pub struct UpdateScheduler {
+    slots: Vec<Vec<(usize, u64)>>,
+    /// the deadline of every scheduled handle
+    deadlines: Vec<Option<u64>>,
+    scheduled: usize,
+    /// the millisec the wheel looks at first, all before it were done
+    cursor: u64,
+    /// the millisec clock without wrapping, and the u32 it last was; the
+    /// clock starts with the first advance
+    now: u64,
+    current: Option<u32>,
+}
+
+impl UpdateScheduler {
+    pub fn new() -> Self {
+        UpdateScheduler {
+            slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+            deadlines: Vec::new(),
+            scheduled: 0,
+            cursor: 0,
+            now: 0,
+            current: None,
+        }
+    }
+
+    /// `time` on the scheduler's clock, which does not wrap
+    fn extend(&self, time: u32) -> u64 {
+        match self.current {
+            Some(last) => max(self.now as i64 + time.wrapping_sub(last) as i32 as i64, 0) as u64,
+            None => self.now,
+        }
+    }
+
+    /// update `handle` at `deadline`, as returned by check, replacing its
+    /// earlier deadline; a deadline already passed, or given before the
+    /// first advance, is due on the next tick
+    pub fn schedule(&mut self, handle: usize, deadline: u32) {
+        let deadline = max(self.extend(deadline), self.cursor);
+        self.insert(handle, deadline);
+    }
+
+    /// update `handle` on the next tick, e.g. after input or send
+    pub fn schedule_now(&mut self, handle: usize) {
+        let cursor = self.cursor;
+        self.insert(handle, cursor);
+    }
+
+    fn insert(&mut self, handle: usize, deadline: u64) {
+        if handle >= self.deadlines.len() {
+            self.deadlines.resize(handle + 1, None);
+        }
+        match self.deadlines[handle] {
+            Some(old) if old == deadline => return,
+            Some(_) => {}
+            None => self.scheduled += 1,
+        }
+        self.deadlines[handle] = Some(deadline);
+        self.slots[deadline as usize % SLOTS].push((handle, deadline));
+    }
+
+    /// forget the deadline of `handle`, e.g. when its session is closed
+    pub fn cancel(&mut self, handle: usize) {
+        if let Some(deadline) = self.deadlines.get_mut(handle) {
+            if deadline.take().is_some() {
+                self.scheduled -= 1;
+            }
+        }
+    }
+
+    pub fn is_scheduled(&self, handle: usize) -> bool {
+        matches!(self.deadlines.get(handle), Some(&Some(_)))
+    }
+
+    /// the number of scheduled handles
+    pub fn len(&self) -> usize {
+        self.scheduled
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scheduled == 0
+    }
+
+    /// millisec from `current` until the next handle is due, None if none
+    /// is scheduled. Looks at most one turn ahead, later deadlines give
+    /// the end of the turn
+    pub fn timeout(&self, current: u32) -> Option<u32> {
+        if self.scheduled == 0 {
+            return None;
+        }
+        let now = self.extend(current);
+        let end = self.cursor + SLOTS as u64;
+        for time in self.cursor..end {
+            let live = self.slots[time as usize % SLOTS].iter().any(|&(handle, deadline)| {
+                deadline == time && self.deadlines[handle] == Some(deadline)
+            });
+            if live {
+                return Some(time.saturating_sub(now) as u32);
+            }
+        }
+        Some(end.saturating_sub(now) as u32)
+    }
+
+    /// move the clock to `current` and append every handle due by then to
+    /// `due`, they are no longer scheduled
+    pub fn advance(&mut self, current: u32, due: &mut Vec<usize>) {
+        let now = self.extend(current);
+        self.now = now;
+        self.current = Some(current);
+        if now < self.cursor {
+            return;
+        }
+        // a full turn visits every slot
+        let end = min(now + 1, self.cursor + SLOTS as u64);
+        for time in self.cursor..end {
+            let slot = &mut self.slots[time as usize % SLOTS];
+            let deadlines = &mut self.deadlines;
+            let scheduled = &mut self.scheduled;
+            slot.retain(|&(handle, deadline)| {
+                if deadlines[handle] != Some(deadline) {
+                    return false;
+                }
+                if deadline > now {
+                    return true;
+                }
+                deadlines[handle] = None;
+                *scheduled -= 1;
+                due.push(handle);
+                false
+            });
+        }
+        self.cursor = now;
+    }
+}
+
+impl Default for UpdateScheduler {
+    fn default() -> Self {
+        UpdateScheduler::new()
+    }
+}
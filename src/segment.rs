@@ -1,6 +1,10 @@
-use fixbuf::ByteBuffer;
+use bytes::Bytes;
+use ByteBuffer;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
-#[derive(Default, Debug)]
+#[derive(Clone, Default, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Segment {
     pub conv: u32,
     pub cmd: u32,
@@ -13,7 +17,11 @@ pub struct Segment {
     pub rto: u32,
     pub fastack: u32,
     pub xmit: u32,
-    pub data: Vec<u8>,
+    /// when the data stops being worth sending, see send_with_deadline
+    pub expire: Option<u32>,
+    /// a slice of the message, shared with the other fragments
+    #[cfg_attr(feature = "serde", serde(with = "payload"))]
+    pub data: Bytes,
 }
 
 impl Segment {
@@ -21,13 +29,18 @@ impl Segment {
         Default::default()
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Self {
+    pub fn from_bytes(data: Bytes) -> Self {
         let mut seg = Segment::new();
-        seg.data = Vec::from(bytes);
+        seg.data = data;
         seg
     }
 
     pub fn encode(&self, buf: &mut ByteBuffer) {
+        self.encode_len(buf, self.data.len());
+    }
+
+    /// the header for a payload of `len` bytes written separately
+    pub fn encode_len(&self, buf: &mut ByteBuffer, len: usize) {
         buf.write_u32(self.conv).unwrap();
         buf.write_u8(self.cmd as u8).unwrap();
         buf.write_u8(self.frg as u8).unwrap();
@@ -35,10 +48,28 @@ impl Segment {
         buf.write_u32(self.ts).unwrap();
         buf.write_u32(self.sn).unwrap();
         buf.write_u32(self.una).unwrap();
-        buf.write_u32(self.data.len() as u32).unwrap();
+        buf.write_u32(len as u32).unwrap();
+    }
+
+    /// whether flush has to (re)transmit this segment at `current`
+    pub fn is_due(&self, current: u32, resent: u32, drained: bool) -> bool {
+        self.xmit == 0 || current >= self.resendts || self.fastack >= resent ||
+        (self.fastack > 0 && drained)
+    }
+}
+
+/// the payload serialized like a Vec<u8>, as before it was shared
+#[cfg(feature = "serde")]
+mod payload {
+    use alloc::vec::Vec;
+    use bytes::Bytes;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(data.iter())
     }
 
-    pub fn data_bytes(&self) -> Vec<u8> {
-        self.data.to_vec()
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        Vec::<u8>::deserialize(deserializer).map(Bytes::from)
     }
 }
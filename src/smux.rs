@@ -0,0 +1,688 @@
+//! Stream multiplexer over a single KCP session, wire compatible with
+//! [xtaci/smux](https://github.com/xtaci/smux) so Go peers interoperate.
+//!
+//! Every frame starts with an 8 byte header: version (u8), cmd (u8),
+//! length (u16) and stream id (u32), little endian. Version 1 carries
+//! SYN/FIN/PSH/NOP only; version 2 adds UPD frames announcing how much each
+//! stream has consumed, which gives per-stream flow control windows.
+//!
+//! `Session` is driven like `KCP`: whoever owns the underlying KCP calls
+//! `pump` after `KCP::update`/`KCP::input` and `update` for keepalive, while
+//! any thread can block on `Stream` reads and writes.
+use std::cmp::min;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use fixbuf::ByteBuffer;
+use kcp::KCP;
+
+/// stream open
+const CMD_SYN: u8 = 0;
+/// stream close, a.k.a EOF mark
+const CMD_FIN: u8 = 1;
+/// data push
+const CMD_PSH: u8 = 2;
+/// no operation, used for keepalive
+const CMD_NOP: u8 = 3;
+/// update window of a stream, version 2 only
+const CMD_UPD: u8 = 4;
+
+/// the size of a frame header
+const HEADER_SIZE: usize = 8;
+/// the size of the payload of CMD_UPD
+const UPD_SIZE: usize = 8;
+/// the window assumed for the peer until its first UPD, as smux's
+/// initialPeerWindow
+const INITIAL_PEER_WINDOW: u32 = 262144;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// protocol version, 1 or 2
+    pub version: u8,
+    /// how often to send a NOP frame, in millisec
+    pub keepalive_interval: u32,
+    /// close the session if nothing arrived for this long, in millisec
+    pub keepalive_timeout: u32,
+    /// the max payload of a PSH frame
+    pub max_frame_size: usize,
+    /// stop reading from the transport while this many bytes are unread
+    pub max_receive_buffer: usize,
+    /// the per-stream window advertised to the peer (version 2)
+    pub max_stream_buffer: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            version: 1,
+            keepalive_interval: 10000,
+            keepalive_timeout: 30000,
+            max_frame_size: 32768,
+            max_receive_buffer: 4194304,
+            max_stream_buffer: 65536,
+        }
+    }
+}
+
+impl Config {
+    fn verify(&self) -> io::Result<()> {
+        if self.version != 1 && self.version != 2 {
+            return Err(invalid("unsupported protocol version"));
+        }
+        if self.keepalive_interval == 0 || self.keepalive_timeout < self.keepalive_interval {
+            return Err(invalid("keepalive timeout must be larger than the interval"));
+        }
+        if self.max_frame_size == 0 || self.max_frame_size > 65535 {
+            return Err(invalid("max frame size must be between 1 and 65535"));
+        }
+        if self.max_receive_buffer == 0 || self.max_stream_buffer == 0 ||
+           self.max_stream_buffer > self.max_receive_buffer {
+            return Err(invalid("invalid receive buffer sizes"));
+        }
+        Ok(())
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn broken(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, msg)
+}
+
+fn write_frame(out: &mut Vec<u8>, version: u8, cmd: u8, sid: u32, data: &[u8]) {
+    let len = data.len();
+    out.extend_from_slice(&[version, cmd, len as u8, (len >> 8) as u8]);
+    out.extend_from_slice(&[sid as u8, (sid >> 8) as u8, (sid >> 16) as u8, (sid >> 24) as u8]);
+    out.extend_from_slice(data);
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    data[0] as u32 | (data[1] as u32) << 8 | (data[2] as u32) << 16 | (data[3] as u32) << 24
+}
+
+struct StreamState {
+    /// received but not yet read
+    buf: VecDeque<u8>,
+    /// the peer sent FIN
+    fin_recv: bool,
+    /// we sent FIN
+    fin_sent: bool,
+    /// the Stream handle was dropped, data for it is discarded
+    detached: bool,
+    /// total bytes read by the application, announced by UPD
+    consumed: u32,
+    /// consumed at the time of the last UPD
+    consumed_sent: u32,
+    /// total bytes written to the peer
+    written: u32,
+    /// the peer's consumed counter and window from its last UPD
+    peer_consumed: u32,
+    peer_window: u32,
+    /// the tasks waiting to read and to write
+    #[cfg(feature = "async")]
+    read_waker: Option<::std::task::Waker>,
+    #[cfg(feature = "async")]
+    write_waker: Option<::std::task::Waker>,
+}
+
+impl StreamState {
+    fn new() -> Self {
+        StreamState {
+            buf: VecDeque::new(),
+            fin_recv: false,
+            fin_sent: false,
+            detached: false,
+            consumed: 0,
+            consumed_sent: 0,
+            written: 0,
+            peer_consumed: 0,
+            peer_window: INITIAL_PEER_WINDOW,
+            #[cfg(feature = "async")]
+            read_waker: None,
+            #[cfg(feature = "async")]
+            write_waker: None,
+        }
+    }
+
+    #[cfg(feature = "async")]
+    fn wake(&mut self) {
+        for waker in self.read_waker.take().into_iter().chain(self.write_waker.take()) {
+            waker.wake();
+        }
+    }
+
+    #[cfg(not(feature = "async"))]
+    fn wake(&mut self) {}
+}
+
+struct Inner {
+    config: Config,
+    next_id: u32,
+    streams: HashMap<u32, StreamState>,
+    accept: VecDeque<u32>,
+    /// bytes of a partially received frame
+    input: Vec<u8>,
+    /// encoded frames waiting for the transport
+    output: Vec<u8>,
+    /// received bytes not yet read, over all streams
+    buffered: usize,
+    /// something arrived since the last keepalive check
+    data_ready: bool,
+    ts_ping: u32,
+    ts_check: u32,
+    updated: bool,
+    closed: bool,
+}
+
+impl Inner {
+    fn frame(&mut self, cmd: u8, sid: u32, data: &[u8]) {
+        let version = self.config.version;
+        write_frame(&mut self.output, version, cmd, sid, data);
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        for stream in self.streams.values_mut() {
+            stream.wake();
+        }
+    }
+
+    /// forget a stream once both sides sent FIN
+    fn reap(&mut self, sid: u32) {
+        let done = match self.streams.get(&sid) {
+            Some(s) => s.fin_sent && s.fin_recv && (s.buf.is_empty() || s.detached),
+            None => false,
+        };
+        if done {
+            self.streams.remove(&sid);
+        }
+    }
+
+    /// parse complete frames from self.input
+    fn parse(&mut self) -> io::Result<()> {
+        let mut pos = 0;
+        while self.input.len() - pos >= HEADER_SIZE {
+            let head = pos;
+            let (version, cmd) = (self.input[head], self.input[head + 1]);
+            let len = self.input[head + 2] as usize | (self.input[head + 3] as usize) << 8;
+            let sid = read_u32(&self.input[head + 4..]);
+            if version != self.config.version {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid protocol"));
+            }
+            if self.input.len() - pos < HEADER_SIZE + len {
+                break;
+            }
+            pos += HEADER_SIZE + len;
+            let data = head + HEADER_SIZE..pos;
+            match cmd {
+                CMD_NOP => {}
+                CMD_SYN => {
+                    if let Entry::Vacant(entry) = self.streams.entry(sid) {
+                        entry.insert(StreamState::new());
+                        self.accept.push_back(sid);
+                    }
+                }
+                CMD_FIN => {
+                    if let Some(stream) = self.streams.get_mut(&sid) {
+                        stream.fin_recv = true;
+                        stream.wake();
+                    }
+                    self.reap(sid);
+                }
+                CMD_PSH => {
+                    if let Some(stream) = self.streams.get_mut(&sid) {
+                        if !stream.detached {
+                            stream.buf.extend(&self.input[data.clone()]);
+                            self.buffered += data.len();
+                            stream.wake();
+                        }
+                    }
+                }
+                CMD_UPD if version == 2 => {
+                    if len < UPD_SIZE {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "short UPD frame"));
+                    }
+                    if let Some(stream) = self.streams.get_mut(&sid) {
+                        stream.peer_consumed = read_u32(&self.input[data.start..]);
+                        stream.peer_window = read_u32(&self.input[data.start + 4..]);
+                        stream.wake();
+                    }
+                }
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid command")),
+            }
+        }
+        self.input.drain(..pos);
+        Ok(())
+    }
+}
+
+struct Shared {
+    inner: Mutex<Inner>,
+    cond: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap()
+    }
+}
+
+/// one end of a multiplexed KCP session
+#[derive(Clone)]
+pub struct Session {
+    shared: Arc<Shared>,
+}
+
+impl Session {
+    /// the dialing side, opens odd stream ids
+    pub fn client(config: Config) -> io::Result<Session> {
+        Session::new(config, 1)
+    }
+
+    /// the accepting side, opens even stream ids
+    pub fn server(config: Config) -> io::Result<Session> {
+        Session::new(config, 0)
+    }
+
+    fn new(config: Config, next_id: u32) -> io::Result<Session> {
+        config.verify()?;
+        Ok(Session {
+            shared: Arc::new(Shared {
+                inner: Mutex::new(Inner {
+                    config,
+                    next_id,
+                    streams: HashMap::new(),
+                    accept: VecDeque::new(),
+                    input: Vec::new(),
+                    output: Vec::new(),
+                    buffered: 0,
+                    data_ready: false,
+                    ts_ping: 0,
+                    ts_check: 0,
+                    updated: false,
+                    closed: false,
+                }),
+                cond: Condvar::new(),
+            }),
+        })
+    }
+
+    /// open a new stream, the peer sees it in `accept_stream`
+    pub fn open_stream(&self) -> io::Result<Stream> {
+        let mut inner = self.shared.lock();
+        if inner.closed {
+            return Err(broken("session closed"));
+        }
+        inner.next_id = inner.next_id.wrapping_add(2);
+        let sid = inner.next_id;
+        if inner.streams.contains_key(&sid) {
+            return Err(io::Error::other("stream id exhausted"));
+        }
+        inner.streams.insert(sid, StreamState::new());
+        inner.frame(CMD_SYN, sid, &[]);
+        Ok(self.stream(sid))
+    }
+
+    /// wait for a stream opened by the peer
+    pub fn accept_stream(&self) -> io::Result<Stream> {
+        let mut inner = self.shared.lock();
+        loop {
+            if let Some(sid) = inner.accept.pop_front() {
+                return Ok(self.stream(sid));
+            }
+            if inner.closed {
+                return Err(broken("session closed"));
+            }
+            inner = self.shared.cond.wait(inner).unwrap();
+        }
+    }
+
+    /// accept a stream opened by the peer without waiting
+    pub fn try_accept_stream(&self) -> Option<Stream> {
+        let sid = self.shared.lock().accept.pop_front();
+        sid.map(|sid| self.stream(sid))
+    }
+
+    fn stream(&self, sid: u32) -> Stream {
+        Stream {
+            id: sid,
+            session: self.clone(),
+            nonblocking: false,
+        }
+    }
+
+    /// the number of streams currently open
+    pub fn num_streams(&self) -> usize {
+        self.shared.lock().streams.len()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().closed
+    }
+
+    /// close the session and every stream in it
+    pub fn close(&self) {
+        self.shared.lock().close();
+        self.shared.cond.notify_all();
+    }
+
+    /// feed bytes read from the transport
+    pub fn input(&self, data: &[u8]) -> io::Result<()> {
+        let mut inner = self.shared.lock();
+        inner.input.extend_from_slice(data);
+        inner.data_ready = true;
+        let result = inner.parse();
+        if result.is_err() {
+            inner.close();
+        }
+        self.shared.cond.notify_all();
+        result
+    }
+
+    /// whether the transport should be read, false while the streams hold
+    /// more than max_receive_buffer unread bytes
+    pub fn wants_input(&self) -> bool {
+        let inner = self.shared.lock();
+        !inner.closed && inner.buffered < inner.config.max_receive_buffer
+    }
+
+    /// take at most `max` bytes of encoded frames for the transport
+    pub fn output(&self, max: usize) -> Vec<u8> {
+        let mut inner = self.shared.lock();
+        let n = min(max, inner.output.len());
+        let data = inner.output.drain(..n).collect();
+        for stream in inner.streams.values_mut() {
+            stream.wake();
+        }
+        self.shared.cond.notify_all();
+        data
+    }
+
+    /// keepalive, call it with the current timestamp in millisec; returns
+    /// false once the session is closed
+    pub fn update(&self, current: u32) -> bool {
+        let mut inner = self.shared.lock();
+        if !inner.updated {
+            inner.updated = true;
+            inner.ts_ping = current;
+            inner.ts_check = current;
+        }
+        if current.wrapping_sub(inner.ts_ping) as i32 >= inner.config.keepalive_interval as i32 {
+            inner.ts_ping = current;
+            inner.frame(CMD_NOP, 0, &[]);
+        }
+        if current.wrapping_sub(inner.ts_check) as i32 >= inner.config.keepalive_timeout as i32 {
+            inner.ts_check = current;
+            if !inner.data_ready {
+                inner.close();
+                self.shared.cond.notify_all();
+            }
+            inner.data_ready = false;
+        }
+        !inner.closed
+    }
+
+    /// exchange data with a KCP session in stream mode, from the thread
+    /// driving it after `KCP::update` or `KCP::input`
+    pub fn pump(&self, kcp: &mut KCP) -> io::Result<()> {
+        let chunk = HEADER_SIZE + self.shared.lock().config.max_frame_size;
+        while kcp.wait_snd() < 2 * kcp.snd_wnd() as isize {
+            let data = self.output(chunk);
+            if data.is_empty() {
+                break;
+            }
+            let mut buf = ByteBuffer::with_capacity(data.len());
+            buf.write_bytes(&data).unwrap();
+            kcp.send(&mut buf);
+        }
+        while self.wants_input() {
+            let size = kcp.peek_size();
+            if size <= 0 {
+                break;
+            }
+            let mut buf = ByteBuffer::with_capacity(size as usize);
+            let n = kcp.recv(&mut buf);
+            if n <= 0 {
+                break;
+            }
+            self.input(&buf.to_bytes()[..n as usize])?;
+        }
+        Ok(())
+    }
+}
+
+/// a logical stream inside a `Session`
+pub struct Stream {
+    id: u32,
+    session: Session,
+    nonblocking: bool,
+}
+
+impl Stream {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// return WouldBlock instead of waiting in read and write
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    /// send FIN, the peer reads EOF once it consumed the data before it
+    pub fn close(&mut self) {
+        let mut inner = self.session.shared.lock();
+        let sent = match inner.streams.get_mut(&self.id) {
+            Some(stream) => {
+                let sent = stream.fin_sent;
+                stream.fin_sent = true;
+                sent
+            }
+            None => true,
+        };
+        if !sent && !inner.closed {
+            inner.frame(CMD_FIN, self.id, &[]);
+        }
+        inner.reap(self.id);
+    }
+
+    /// read into buf, None if the caller has to wait
+    fn try_read(&self, inner: &mut Inner, buf: &mut [u8]) -> Option<io::Result<usize>> {
+        let version = inner.config.version;
+        let window = inner.config.max_stream_buffer as u32;
+        let (n, update) = match inner.streams.get_mut(&self.id) {
+            Some(stream) => {
+                if stream.buf.is_empty() {
+                    if stream.fin_recv {
+                        return Some(Ok(0));
+                    }
+                    if inner.closed {
+                        return Some(Err(broken("session closed")));
+                    }
+                    return None;
+                }
+                let n = min(buf.len(), stream.buf.len());
+                for (i, b) in stream.buf.drain(..n).enumerate() {
+                    buf[i] = b;
+                }
+                stream.consumed = stream.consumed.wrapping_add(n as u32);
+                let update = version == 2 &&
+                             stream.consumed.wrapping_sub(stream.consumed_sent) >= window / 2;
+                if update {
+                    stream.consumed_sent = stream.consumed;
+                }
+                (n, if update { Some(stream.consumed) } else { None })
+            }
+            None => return Some(Ok(0)),
+        };
+        inner.buffered -= n;
+        if let Some(consumed) = update {
+            let mut upd = Vec::with_capacity(UPD_SIZE);
+            for v in &[consumed, window] {
+                upd.extend_from_slice(&[*v as u8, (*v >> 8) as u8, (*v >> 16) as u8, (*v >> 24) as u8]);
+            }
+            inner.frame(CMD_UPD, self.id, &upd);
+        }
+        Some(Ok(n))
+    }
+
+    /// queue frames for buf, None if the caller has to wait
+    fn try_write(&self, inner: &mut Inner, buf: &[u8]) -> Option<io::Result<usize>> {
+        if inner.closed {
+            return Some(Err(broken("session closed")));
+        }
+        if inner.output.len() >= inner.config.max_frame_size * 8 {
+            return None;
+        }
+        let version = inner.config.version;
+        let mut n = min(buf.len(), inner.config.max_frame_size);
+        match inner.streams.get_mut(&self.id) {
+            Some(ref stream) if stream.fin_sent => {
+                return Some(Err(broken("stream closed")));
+            }
+            Some(stream) => {
+                if version == 2 {
+                    let inflight = stream.written.wrapping_sub(stream.peer_consumed);
+                    let window = stream.peer_window.saturating_sub(inflight) as usize;
+                    if window == 0 {
+                        return None;
+                    }
+                    n = min(n, window);
+                }
+                stream.written = stream.written.wrapping_add(n as u32);
+            }
+            None => return Some(Err(broken("stream closed"))),
+        }
+        inner.frame(CMD_PSH, self.id, &buf[..n]);
+        Some(Ok(n))
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut inner = self.session.shared.lock();
+        loop {
+            if let Some(result) = self.try_read(&mut inner, buf) {
+                return result;
+            }
+            if self.nonblocking {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            inner = self.session.shared.cond.wait(inner).unwrap();
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut inner = self.session.shared.lock();
+        loop {
+            if let Some(result) = self.try_write(&mut inner, buf) {
+                return result;
+            }
+            if self.nonblocking {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            inner = self.session.shared.cond.wait(inner).unwrap();
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        {
+            let mut inner = self.session.shared.lock();
+            let unread = match inner.streams.get_mut(&self.id) {
+                Some(stream) => {
+                    stream.detached = true;
+                    stream.buf.drain(..).count()
+                }
+                None => 0,
+            };
+            inner.buffered -= unread;
+        }
+        self.close();
+        self.session.shared.cond.notify_all();
+    }
+}
+
+#[cfg(feature = "async")]
+mod async_io {
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+    use futures_io::{AsyncRead, AsyncWrite};
+    use super::Stream;
+
+    /// wait in `slot` for `waker`, replacing the task waiting there before
+    fn park(slot: &mut Option<Waker>, waker: &Waker) {
+        if !slot.as_ref().is_some_and(|parked| parked.will_wake(waker)) {
+            *slot = Some(waker.clone());
+        }
+    }
+
+    impl AsyncRead for Stream {
+        fn poll_read(self: Pin<&mut Self>,
+                     cx: &mut Context,
+                     buf: &mut [u8])
+                     -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            let mut inner = this.session.shared.lock();
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            match this.try_read(&mut inner, buf) {
+                Some(result) => Poll::Ready(result),
+                None => {
+                    if let Some(stream) = inner.streams.get_mut(&this.id) {
+                        park(&mut stream.read_waker, cx.waker());
+                    }
+                    Poll::Pending
+                }
+            }
+        }
+    }
+
+    impl AsyncWrite for Stream {
+        fn poll_write(self: Pin<&mut Self>,
+                      cx: &mut Context,
+                      buf: &[u8])
+                      -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            let mut inner = this.session.shared.lock();
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            match this.try_write(&mut inner, buf) {
+                Some(result) => Poll::Ready(result),
+                None => {
+                    if let Some(stream) = inner.streams.get_mut(&this.id) {
+                        park(&mut stream.write_waker, cx.waker());
+                    }
+                    Poll::Pending
+                }
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            self.get_mut().close();
+            Poll::Ready(Ok(()))
+        }
+    }
+}
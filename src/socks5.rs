@@ -0,0 +1,153 @@
+//! Minimal SOCKS5 (RFC 1928) server side handshake, supporting the
+//! no-authentication method and the CONNECT command only.
+//!
+//! The requested destination is kept in its SOCKS5 encoding
+//! (ATYP, DST.ADDR, DST.PORT) and sent as the first bytes of the KCP stream,
+//! so the remote end can decode it with `decode_addr`. The client is only
+//! answered with `reply` once that end connected, or failed to.
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_NONE: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+pub const REP_SUCCEEDED: u8 = 0;
+const REP_GENERAL_FAILURE: u8 = 1;
+const REP_NOT_ALLOWED: u8 = 2;
+const REP_NETWORK_UNREACHABLE: u8 = 3;
+const REP_HOST_UNREACHABLE: u8 = 4;
+const REP_CONNECTION_REFUSED: u8 = 5;
+const REP_CMD_UNSUPPORTED: u8 = 7;
+const REP_ATYP_UNSUPPORTED: u8 = 8;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// answer a request with the reply code `rep`
+pub fn reply<S: Write>(stream: &mut S, rep: u8) -> io::Result<()> {
+    stream.write_all(&[VERSION, rep, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+}
+
+/// the reply code for a failed connect to the destination
+pub fn reply_code(err: &io::Error) -> u8 {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => REP_CONNECTION_REFUSED,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::TimedOut => REP_HOST_UNREACHABLE,
+        io::ErrorKind::NetworkUnreachable => REP_NETWORK_UNREACHABLE,
+        io::ErrorKind::PermissionDenied => REP_NOT_ALLOWED,
+        _ => REP_GENERAL_FAILURE,
+    }
+}
+
+/// run the handshake on a freshly accepted connection up to the CONNECT
+/// request and return its encoded destination address, to be answered
+/// with `reply` after connecting
+pub fn accept<S: Read + Write>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut head = [0; 2];
+    stream.read_exact(&mut head)?;
+    if head[0] != VERSION {
+        return Err(invalid("not a SOCKS5 client"));
+    }
+    let mut methods = vec![0; head[1] as usize];
+    stream.read_exact(&mut methods)?;
+    if !methods.contains(&METHOD_NO_AUTH) {
+        stream.write_all(&[VERSION, METHOD_NONE])?;
+        return Err(invalid("no acceptable authentication method"));
+    }
+    stream.write_all(&[VERSION, METHOD_NO_AUTH])?;
+
+    let mut req = [0; 4];
+    stream.read_exact(&mut req)?;
+    if req[0] != VERSION {
+        return Err(invalid("not a SOCKS5 request"));
+    }
+    if req[1] != CMD_CONNECT {
+        reply(stream, REP_CMD_UNSUPPORTED)?;
+        return Err(invalid("only CONNECT is supported"));
+    }
+    let mut addr = vec![req[3]];
+    let len = match req[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            addr.push(len[0]);
+            len[0] as usize
+        }
+        _ => {
+            reply(stream, REP_ATYP_UNSUPPORTED)?;
+            return Err(invalid("unsupported address type"));
+        }
+    };
+    let start = addr.len();
+    addr.resize(start + len + 2, 0);
+    stream.read_exact(&mut addr[start..])?;
+    Ok(addr)
+}
+
+/// encode `host:port` as ATYP, DST.ADDR, DST.PORT, fails for domain names
+/// longer than 255 bytes
+pub fn encode_addr(host: &str, port: u16) -> io::Result<Vec<u8>> {
+    let mut addr = Vec::new();
+    if let Ok(ip) = host.parse::<Ipv4Addr>() {
+        addr.push(ATYP_IPV4);
+        addr.extend_from_slice(&ip.octets());
+    } else if let Ok(ip) = host.parse::<Ipv6Addr>() {
+        addr.push(ATYP_IPV6);
+        addr.extend_from_slice(&ip.octets());
+    } else {
+        if host.len() > 255 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "domain name too long"));
+        }
+        addr.push(ATYP_DOMAIN);
+        addr.push(host.len() as u8);
+        addr.extend_from_slice(host.as_bytes());
+    }
+    addr.push((port >> 8) as u8);
+    addr.push(port as u8);
+    Ok(addr)
+}
+
+/// decode an address written by `accept` or `encode_addr` into a string
+/// for `ToSocketAddrs` and the number of bytes consumed,
+/// returns Ok(None) if more data is needed
+pub fn decode_addr(data: &[u8]) -> io::Result<Option<(String, usize)>> {
+    if data.is_empty() {
+        return Ok(None);
+    }
+    let (host, end) = match data[0] {
+        ATYP_IPV4 => {
+            if data.len() < 7 {
+                return Ok(None);
+            }
+            (Ipv4Addr::new(data[1], data[2], data[3], data[4]).to_string(), 5)
+        }
+        ATYP_IPV6 => {
+            if data.len() < 19 {
+                return Ok(None);
+            }
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&data[1..17]);
+            (format!("[{}]", Ipv6Addr::from(octets)), 17)
+        }
+        ATYP_DOMAIN => {
+            if data.len() < 2 || data.len() < 2 + data[1] as usize + 2 {
+                return Ok(None);
+            }
+            let end = 2 + data[1] as usize;
+            match String::from_utf8(data[2..end].to_vec()) {
+                Ok(host) => (host, end),
+                Err(_) => return Err(invalid("invalid domain name")),
+            }
+        }
+        _ => return Err(invalid("unsupported address type")),
+    };
+    let port = (data[end] as u16) << 8 | data[end + 1] as u16;
+    Ok(Some((format!("{}:{}", host, port), end + 2)))
+}
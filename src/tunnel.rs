@@ -0,0 +1,812 @@
+//! kcptun-style TCP forwarding over KCP.
+//!
+//! The client accepts TCP connections and carries each one in its own KCP
+//! session (stream mode) over UDP to the server, which demultiplexes the
+//! sessions by source address and conv and forwards them to a fixed TCP
+//! target. With migration on, the client appends a random session token to
+//! its datagrams and the server finds sessions by conv and token instead,
+//! following a client whose address changed. In SOCKS5 mode the client asks
+//! each connection for its destination and sends it ahead of the data, and
+//! the server connects to whatever each session names, answering with the
+//! SOCKS5 reply code as the first byte of its stream, which the client passes
+//! on. A TCP end of file travels as a FIN control datagram, sent once the data
+//! before it was acked, and a session closes once both directions finished.
+//! Everything is driven by plain std sockets, one thread per client
+//! connection and a single polling loop on the server, which connects to
+//! targets on worker threads.
+use std::cell::RefCell;
+use std::cmp::max;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+use fixbuf::ByteBuffer;
+use rand;
+use time;
+use kcp::{get_conv, opens_session, KCP};
+use batch::{BATCH_SIZE, RecvBatch, SendBatch};
+use limiter::RateLimiter;
+use socks5;
+
+/// how long a socket read blocks before the sessions are updated again
+const POLL_INTERVAL: u64 = 5;
+/// the size of buffers used for reading from sockets
+const BUF_SIZE: usize = 65536;
+/// the size of the session token appended to client datagrams
+const TOKEN_LEN: usize = 8;
+/// tunnel control datagrams, conv + cmd + nonce, told apart from KCP
+/// packets by the cmd after conv
+const CMD_PATH_CHALLENGE: u8 = 120;
+const CMD_PATH_RESPONSE: u8 = 121;
+/// the sender read to the end of its TCP stream and all data before it was
+/// acked, answered with a FIN_ACK
+const CMD_FIN: u8 = 122;
+const CMD_FIN_ACK: u8 = 123;
+const CONTROL_LEN: usize = 13;
+/// how often an unanswered FIN is sent again, in millisec
+const FIN_INTERVAL: u32 = 200;
+
+/// current timestamp in millisec, suitable for `KCP::update`
+pub fn clock() -> u32 {
+    (time::precise_time_ns() / 1000000) as u32
+}
+
+/// a path challenge or response carrying `nonce`
+fn control(conv: u32, cmd: u8, nonce: u64) -> Vec<u8> {
+    let mut buf = ByteBuffer::with_capacity(CONTROL_LEN);
+    buf.write_u32(conv).unwrap();
+    buf.write_u8(cmd).unwrap();
+    buf.write_bytes(&nonce.to_le_bytes()).unwrap();
+    buf.to_bytes()[..CONTROL_LEN].to_vec()
+}
+
+/// the nonce of a control datagram of type `cmd`
+fn control_nonce(data: &[u8], cmd: u8) -> Option<u64> {
+    if data.len() != CONTROL_LEN || data[4] != cmd {
+        return None;
+    }
+    let mut nonce = [0; 8];
+    nonce.copy_from_slice(&data[5..]);
+    Some(u64::from_le_bytes(nonce))
+}
+
+fn seal(packet: &[u8], token: u64) -> Vec<u8> {
+    let mut data = packet.to_vec();
+    data.extend_from_slice(&token.to_le_bytes());
+    data
+}
+
+/// split a client datagram into the packet and its session token
+fn unseal(data: &[u8]) -> Option<(&[u8], u64)> {
+    if data.len() < TOKEN_LEN {
+        return None;
+    }
+    let (packet, tail) = data.split_at(data.len() - TOKEN_LEN);
+    let mut token = [0; TOKEN_LEN];
+    token.copy_from_slice(tail);
+    Some((packet, u64::from_le_bytes(token)))
+}
+
+/// KCP knobs shared by both ends of the tunnel
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub mtu: isize,
+    /// probe for a path MTU up to this size when larger than mtu
+    pub mtu_max: isize,
+    pub sndwnd: isize,
+    pub rcvwnd: isize,
+    pub nodelay: isize,
+    pub interval: isize,
+    pub resend: isize,
+    pub nc: isize,
+    /// close a session after this many millisec without traffic
+    pub timeout: u32,
+    /// bytes per second each session may send, 0 for no limit
+    pub rate: u32,
+    /// shared by all sessions holding a clone of this config
+    pub limiter: Option<RateLimiter>,
+    /// find sessions by conv and a token the client appends to every
+    /// datagram instead of by address, so they survive an address change;
+    /// both ends must agree
+    pub migrate: bool,
+    /// server with migrate: only send to a new client address once it
+    /// echoed a random nonce sent there, a return-routability check that
+    /// keeps a forged source address from redirecting a session. It does
+    /// not authenticate the client: token and nonce travel in clear, so an
+    /// on-path attacker can still answer the challenge
+    pub validate_path: bool,
+    /// hand runs of full sized datagrams to the kernel at once and receive
+    /// coalesced ones, where Linux supports UDP GSO and GRO
+    pub gso: bool,
+    /// server: the most sessions served at once, datagrams opening more
+    /// are dropped
+    pub max_sessions: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            mtu: 1400,
+            mtu_max: 0,
+            sndwnd: 128,
+            rcvwnd: 128,
+            nodelay: 1,
+            interval: 20,
+            resend: 2,
+            nc: 1,
+            timeout: 60000,
+            rate: 0,
+            limiter: None,
+            migrate: false,
+            validate_path: false,
+            gso: false,
+            max_sessions: 1024,
+        }
+    }
+}
+
+impl Config {
+    fn apply(&self, kcp: &mut KCP) {
+        kcp.set_stream(true);
+        if self.mtu_max > self.mtu {
+            kcp.set_mtu_discovery(self.mtu, self.mtu_max);
+        } else {
+            kcp.set_mtu(self.mtu);
+        }
+        kcp.wnd_size(self.sndwnd, self.rcvwnd);
+        kcp.no_delay(self.nodelay, self.interval, self.resend, self.nc);
+        kcp.set_rate_limit(self.rate, self.rate / 10);
+        kcp.set_shared_limiter(self.limiter.clone());
+    }
+
+    /// the largest datagram a peer with this config sends
+    fn datagram_size(&self) -> usize {
+        max(self.mtu, self.mtu_max) as usize + TOKEN_LEN
+    }
+
+    /// batches for `udp`, with offload if configured and supported
+    fn batches(&self, udp: &UdpSocket) -> (RecvBatch, SendBatch) {
+        let mut received = RecvBatch::new(BATCH_SIZE, self.datagram_size());
+        let mut batch = SendBatch::new();
+        if self.gso {
+            batch.enable_gso(udp);
+            received.enable_gro(udp);
+        }
+        (received, batch)
+    }
+}
+
+/// command line options of the tunnel binaries
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// local address to listen on
+    pub listen: String,
+    /// server UDP address for the client, TCP target for the server
+    pub remote: String,
+    /// act as a SOCKS5 proxy instead of forwarding to a fixed target
+    pub socks5: bool,
+    pub config: Config,
+}
+
+impl Options {
+    /// parse `--flag value` pairs, the remote address is given by either
+    /// `--remote` (client) or `--target` (server) and may only be omitted
+    /// by a server started with `--socks5`
+    pub fn parse<I>(args: I) -> Result<Options, String>
+        where I: IntoIterator<Item = String>
+    {
+        let mut opts: Options = Default::default();
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            if flag == "--socks5" {
+                opts.socks5 = true;
+                continue;
+            }
+            if flag == "--gso" {
+                opts.config.gso = true;
+                continue;
+            }
+            if flag == "--migrate" || flag == "--validate-path" {
+                opts.config.migrate = true;
+                opts.config.validate_path |= flag == "--validate-path";
+                continue;
+            }
+            let value = match args.next() {
+                Some(value) => value,
+                None => return Err(format!("missing value for {}", flag)),
+            };
+            let num = || value.parse::<isize>().map_err(|_| format!("invalid value for {}", flag));
+            match flag.as_str() {
+                "-l" | "--listen" => opts.listen = value.clone(),
+                "-r" | "--remote" | "-t" | "--target" => opts.remote = value.clone(),
+                "--mtu" => opts.config.mtu = num()?,
+                "--mtu-max" => opts.config.mtu_max = num()?,
+                "--sndwnd" => opts.config.sndwnd = num()?,
+                "--rcvwnd" => opts.config.rcvwnd = num()?,
+                "--nodelay" => opts.config.nodelay = num()?,
+                "--interval" => opts.config.interval = num()?,
+                "--resend" => opts.config.resend = num()?,
+                "--nc" => opts.config.nc = num()?,
+                "--timeout" => {
+                    opts.config.timeout = value.parse::<u32>()
+                        .ok()
+                        .and_then(|secs| secs.checked_mul(1000))
+                        .filter(|&ms| ms <= i32::MAX as u32)
+                        .ok_or_else(|| format!("invalid value for {}", flag))?;
+                }
+                "--rate" => opts.config.rate = num()? as u32,
+                "--sessions" => opts.config.max_sessions = num()? as usize,
+                "--rate-total" => {
+                    let rate = num()? as u32;
+                    opts.config.limiter = Some(RateLimiter::new(rate, rate / 10));
+                }
+                _ => return Err(format!("unknown flag {}", flag)),
+            }
+        }
+        if opts.listen.is_empty() {
+            return Err("the listen address is required".to_string());
+        }
+        if opts.remote.is_empty() && !opts.socks5 {
+            return Err("the remote address is required".to_string());
+        }
+        Ok(opts)
+    }
+}
+
+/// usage text for the KCP knobs shared by both binaries
+pub const USAGE_KCP: &str = "    --mtu <n>        MTU of KCP packets (default 1400)
+    --mtu-max <n>    discover the path MTU between --mtu and n
+    --sndwnd <n>     send window in packets (default 128)
+    --rcvwnd <n>     receive window in packets (default 128)
+    --nodelay <n>    0: disable, 1: enable nodelay (default 1)
+    --interval <ms>  internal update interval (default 20)
+    --resend <n>     fast resend after n skipped acks, 0 disables (default 2)
+    --nc <n>         1: disable congestion control (default 1)
+    --timeout <s>    close idle sessions after s seconds (default 60)
+    --rate <n>       limit each session to n bytes per second
+    --rate-total <n> limit all sessions together to n bytes per second
+    --sessions <n>   server: serve at most n sessions at once (default 1024)
+    --gso            use UDP segmentation offload where supported (Linux)
+    --migrate        keep sessions across client address changes
+    --validate-path  server: challenge a new client address before using it
+    --socks5         client: accept SOCKS5, server: connect to requested targets";
+
+fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    match addr.to_socket_addrs()?.next() {
+        Some(addr) => Ok(addr),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput, "no address resolved")),
+    }
+}
+
+/// one TCP connection carried by one KCP session
+struct Session {
+    kcp: KCP,
+    outbox: Rc<RefCell<VecDeque<Vec<u8>>>>,
+    /// None until a dynamic target is connected
+    tcp: Option<TcpStream>,
+    connecting: Option<Receiver<io::Result<TcpStream>>>,
+    /// server in SOCKS5 mode: send the SOCKS5 reply code of the connect
+    /// as the first byte of the stream
+    reply: bool,
+    /// the connect failed: the session only lives until its reply code was
+    /// acked, or with a fixed target until it closed like after an eof, so
+    /// retransmits of the first packet do not open it again
+    refused: bool,
+    /// data received from KCP but not yet written to the TCP side
+    pending: Vec<u8>,
+    eof: bool,
+    /// when the last FIN was sent
+    fin_sent: Option<u32>,
+    fin_acked: bool,
+    /// the peer sent a FIN, the TCP side is shut down for writing once
+    /// pending is written
+    peer_fin: bool,
+    last_active: u32,
+    config: Config,
+    /// where the server sends the session's datagrams, None on the client
+    /// whose socket is connected
+    addr: Option<SocketAddr>,
+    /// with migration: appended to each datagram by the client, expected
+    /// on each datagram by the server
+    token: Option<u64>,
+    /// the new address challenged by the server and the nonce it sent
+    challenge: Option<(SocketAddr, u64)>,
+}
+
+impl Session {
+    fn new(conv: u32, config: &Config) -> Session {
+        let outbox = Rc::new(RefCell::new(VecDeque::new()));
+        let out = outbox.clone();
+        let mut kcp = KCP::new(conv, move |buf: &mut ByteBuffer, size: usize| {
+            out.borrow_mut().push_back(buf.to_bytes()[..size].to_vec());
+        });
+        config.apply(&mut kcp);
+        Session {
+            kcp,
+            outbox,
+            tcp: None,
+            connecting: None,
+            reply: false,
+            refused: false,
+            pending: Vec::new(),
+            eof: false,
+            fin_sent: None,
+            fin_acked: false,
+            peer_fin: false,
+            last_active: clock(),
+            config: config.clone(),
+            addr: None,
+            token: None,
+            challenge: None,
+        }
+    }
+
+    /// start moving data between `tcp` and the session
+    fn attach(&mut self, tcp: TcpStream) -> io::Result<()> {
+        tcp.set_nonblocking(true)?;
+        tcp.set_nodelay(true)?;
+        self.tcp = Some(tcp);
+        Ok(())
+    }
+
+    /// feed a datagram received from the peer, returns whether KCP
+    /// accepted it
+    fn input(&mut self, data: &[u8], current: u32) -> bool {
+        if self.kcp.input_slice(data) < 0 {
+            return false;
+        }
+        self.last_active = current;
+        true
+    }
+
+    /// handle a FIN or FIN_ACK from the peer, returns false for any other
+    /// datagram
+    fn closing(&mut self, data: &[u8], current: u32) -> bool {
+        if control_nonce(data, CMD_FIN).is_some() {
+            self.peer_fin = true;
+            let ack = control(self.kcp.conv(), CMD_FIN_ACK, 0);
+            self.outbox.borrow_mut().push_back(ack);
+        } else if control_nonce(data, CMD_FIN_ACK).is_some() {
+            self.fin_acked = self.fin_sent.is_some();
+        } else {
+            return false;
+        }
+        self.last_active = current;
+        true
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        let mut buf = ByteBuffer::with_capacity(data.len());
+        buf.write_bytes(data).unwrap();
+        self.kcp.send(&mut buf);
+    }
+
+    /// append the next chunk received from KCP to pending
+    fn recv(&mut self) -> bool {
+        let size = self.kcp.peek_size();
+        if size <= 0 {
+            return false;
+        }
+        let mut data = ByteBuffer::with_capacity(size as usize);
+        let n = self.kcp.recv(&mut data);
+        if n <= 0 {
+            return false;
+        }
+        self.pending.extend_from_slice(&data.to_bytes()[..n as usize]);
+        true
+    }
+
+    /// client in SOCKS5 mode: the reply code the server sends once it
+    /// connected, None until it arrived
+    fn reply_code(&mut self, current: u32) -> Option<u8> {
+        self.kcp.update(current);
+        if self.pending.is_empty() && !self.recv() {
+            return None;
+        }
+        Some(self.pending.remove(0))
+    }
+
+    /// connect to `addr` on a worker thread, so the polling loop goes on
+    fn start_connect<A: ToSocketAddrs + Send + 'static>(&mut self, addr: A) {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || tx.send(TcpStream::connect(addr)));
+        self.connecting = Some(rx);
+    }
+
+    /// finish the connect to the target, or start it once a SOCKS5 client
+    /// sent the destination, returns false if the session cannot be served
+    fn connect(&mut self) -> bool {
+        let result = match self.connecting {
+            Some(ref rx) => rx.try_recv(),
+            None => return self.request(),
+        };
+        match result {
+            Ok(Ok(tcp)) => {
+                if self.reply {
+                    self.send(&[socks5::REP_SUCCEEDED]);
+                }
+                self.attach(tcp).is_ok()
+            }
+            Ok(Err(ref e)) => {
+                if self.reply {
+                    self.send(&[socks5::reply_code(e)]);
+                } else {
+                    self.eof = true;
+                }
+                self.refused = true;
+                true
+            }
+            Err(TryRecvError::Empty) => true,
+            Err(TryRecvError::Disconnected) => false,
+        }
+    }
+
+    /// decode the destination a SOCKS5 client sent ahead of the data and
+    /// start connecting to it once it is complete
+    fn request(&mut self) -> bool {
+        while self.recv() {}
+        match socks5::decode_addr(&self.pending) {
+            Ok(Some((addr, n))) => {
+                self.pending.drain(..n);
+                self.start_connect(addr);
+                true
+            }
+            Ok(None) => true,
+            Err(_) => false,
+        }
+    }
+
+    /// move data between TCP and KCP, returns false once the session is over
+    fn pump(&mut self, current: u32, buf: &mut [u8]) -> bool {
+        if self.refused {
+            self.kcp.update(current);
+            if self.reply {
+                return self.kcp.wait_snd() > 0 && self.alive(current);
+            }
+            while self.recv() {
+                self.pending.clear();
+            }
+            self.send_fin(current);
+            return self.alive(current);
+        }
+        if self.tcp.is_none() {
+            self.kcp.update(current);
+            if !self.connect() {
+                return false;
+            }
+            if self.tcp.is_none() {
+                return self.alive(current);
+            }
+        }
+        while !self.eof && self.kcp.wait_snd() < self.config.sndwnd * 2 {
+            match self.tcp.as_mut().unwrap().read(buf) {
+                Ok(0) => self.eof = true,
+                Ok(n) => {
+                    self.send(&buf[..n]);
+                    self.last_active = current;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => return false,
+            }
+        }
+        self.send_fin(current);
+        self.kcp.update(current);
+        loop {
+            if self.pending.is_empty() && !self.recv() {
+                if self.peer_fin {
+                    let _ = self.tcp.as_ref().unwrap().shutdown(Shutdown::Write);
+                }
+                break;
+            }
+            match self.tcp.as_mut().unwrap().write(&self.pending) {
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => return false,
+            }
+        }
+        self.alive(current)
+    }
+
+    /// after an eof, send a FIN once the data before it was acked and again
+    /// every FIN_INTERVAL until the peer acks it
+    fn send_fin(&mut self, current: u32) {
+        if self.eof && !self.fin_acked && self.kcp.wait_snd() == 0 &&
+           self.fin_sent.is_none_or(|sent| current.wrapping_sub(sent) >= FIN_INTERVAL) {
+            let fin = control(self.kcp.conv(), CMD_FIN, 0);
+            self.outbox.borrow_mut().push_back(fin);
+            self.fin_sent = Some(current);
+        }
+    }
+
+    /// false once both ends finished, the link died or the session idled
+    /// past the timeout
+    fn alive(&self, current: u32) -> bool {
+        let done = self.fin_acked && self.peer_fin && self.pending.is_empty();
+        !done && !self.kcp.is_dead_link() &&
+        (current.wrapping_sub(self.last_active) as i32) < self.config.timeout as i32
+    }
+
+    /// queue the datagrams produced by the last flush, the client seals
+    /// them with its token
+    fn drain(&mut self, batch: &mut SendBatch) {
+        while let Some(packet) = self.outbox.borrow_mut().pop_front() {
+            match (self.addr, self.token) {
+                (None, Some(token)) => batch.push(seal(&packet, token), None),
+                (addr, _) => batch.push(packet, addr),
+            }
+        }
+    }
+}
+
+/// accepts TCP connections and tunnels each one to the server
+pub struct Client {
+    listener: TcpListener,
+    remote: SocketAddr,
+    socks5: bool,
+    config: Config,
+}
+
+impl Client {
+    pub fn new(listener: TcpListener, remote: SocketAddr, config: Config) -> Self {
+        Client {
+            listener,
+            remote,
+            socks5: false,
+            config,
+        }
+    }
+
+    pub fn bind(opts: &Options) -> io::Result<Self> {
+        let listener = TcpListener::bind(resolve(&opts.listen)?)?;
+        let mut client = Client::new(listener, resolve(&opts.remote)?, opts.config.clone());
+        client.set_socks5(opts.socks5);
+        Ok(client)
+    }
+
+    /// SOCKS5 mode: each connection names its own destination
+    pub fn set_socks5(&mut self, socks5: bool) {
+        self.socks5 = socks5;
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// accept connections forever
+    pub fn run(&self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let tcp = stream?;
+            let (remote, socks5, config) = (self.remote, self.socks5, self.config.clone());
+            thread::spawn(move || Client::handle(tcp, remote, socks5, config));
+        }
+        Ok(())
+    }
+
+    fn handle(mut tcp: TcpStream,
+              remote: SocketAddr,
+              socks5: bool,
+              config: Config)
+              -> io::Result<()> {
+        let target = if socks5 {
+            Some(socks5::accept(&mut tcp)?)
+        } else {
+            None
+        };
+        let local: SocketAddr = if remote.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let udp = UdpSocket::bind(local)?;
+        udp.connect(remote)?;
+        udp.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL)))?;
+        let conv = rand::random::<u32>();
+        let mut session = Session::new(conv, &config);
+        if config.migrate {
+            session.token = Some(rand::random::<u64>());
+        }
+        // a SOCKS5 client is only answered once the server connected
+        let mut waiting = None;
+        match target {
+            Some(target) => {
+                session.send(&target);
+                waiting = Some(tcp);
+            }
+            None => session.attach(tcp)?,
+        }
+        let mut buf = vec![0; BUF_SIZE];
+        let (mut received, mut batch) = config.batches(&udp);
+        loop {
+            match received.recv(&udp) {
+                Ok(n) => {
+                    for i in 0..n {
+                        let (data, _) = received.get(i);
+                        match (control_nonce(data, CMD_PATH_CHALLENGE), session.token) {
+                            (Some(nonce), Some(token)) => {
+                                let response = control(conv, CMD_PATH_RESPONSE, nonce);
+                                batch.push(seal(&response, token), None);
+                            }
+                            _ => {
+                                if !session.closing(data, clock()) {
+                                    session.input(data, clock());
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+            let current = clock();
+            let alive = if waiting.is_none() {
+                session.pump(current, &mut buf)
+            } else if let Some(rep) = session.reply_code(current) {
+                let mut tcp = waiting.take().unwrap();
+                socks5::reply(&mut tcp, rep)?;
+                if rep == socks5::REP_SUCCEEDED {
+                    session.attach(tcp)?;
+                } else {
+                    // ack the code before leaving
+                    session.kcp.flush();
+                }
+                rep == socks5::REP_SUCCEEDED
+            } else {
+                session.alive(current)
+            };
+            session.drain(&mut batch);
+            batch.send(&udp);
+            if !alive {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// the client address and conv of a session, the address is None with
+/// migration where the token tells clients apart
+type SessionKey = (Option<SocketAddr>, u32);
+
+/// receives tunnelled sessions and forwards them to the TCP target
+pub struct Server {
+    udp: UdpSocket,
+    /// None in SOCKS5 mode, where every session names its target
+    target: Option<SocketAddr>,
+    config: Config,
+}
+
+impl Server {
+    pub fn new(udp: UdpSocket, target: SocketAddr, config: Config) -> Self {
+        Server {
+            udp,
+            target: Some(target),
+            config,
+        }
+    }
+
+    /// serve SOCKS5 clients, connecting to the target each session requests
+    pub fn socks5(udp: UdpSocket, config: Config) -> Self {
+        Server {
+            udp,
+            target: None,
+            config,
+        }
+    }
+
+    pub fn bind(opts: &Options) -> io::Result<Self> {
+        let udp = UdpSocket::bind(resolve(&opts.listen)?)?;
+        if opts.remote.is_empty() {
+            Ok(Server::socks5(udp, opts.config.clone()))
+        } else {
+            Ok(Server::new(udp, resolve(&opts.remote)?, opts.config.clone()))
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.udp.local_addr()
+    }
+
+    /// serve sessions forever
+    pub fn run(&self) -> io::Result<()> {
+        let mut sessions: HashMap<SessionKey, Session> = HashMap::new();
+        let mut buf = vec![0; BUF_SIZE];
+        let (mut received, mut batch) = self.config.batches(&self.udp);
+        self.udp.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL)))?;
+        loop {
+            match received.recv(&self.udp) {
+                Ok(n) => {
+                    for i in 0..n {
+                        let (data, addr) = received.get(i);
+                        self.dispatch(&mut sessions, data, addr);
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+            let current = clock();
+            let mut closed = Vec::new();
+            for (key, session) in &mut sessions {
+                if !session.pump(current, &mut buf) {
+                    closed.push(*key);
+                }
+                session.drain(&mut batch);
+            }
+            batch.send(&self.udp);
+            for key in closed {
+                sessions.remove(&key);
+            }
+        }
+    }
+    /// hand a datagram from `addr` to its session, creating the session on
+    /// a packet that opens one and following it to a new address
+    fn dispatch(&self,
+                sessions: &mut HashMap<SessionKey, Session>,
+                data: &[u8],
+                addr: SocketAddr) {
+        let (data, token) = if self.config.migrate {
+            match unseal(data) {
+                Some((packet, token)) => (packet, Some(token)),
+                None => return,
+            }
+        } else {
+            (data, None)
+        };
+        let conv = match get_conv(data) {
+            Some(conv) => conv,
+            None => return,
+        };
+        let key = (if self.config.migrate { None } else { Some(addr) }, conv);
+        if !sessions.contains_key(&key) {
+            if control_nonce(data, CMD_FIN).is_some() {
+                // the session closed before the peer heard our FIN_ACK
+                let _ = self.udp.send_to(&control(conv, CMD_FIN_ACK, 0), addr);
+                return;
+            }
+            if !opens_session(data) || sessions.len() >= self.config.max_sessions {
+                return;
+            }
+            let mut session = Session::new(conv, &self.config);
+            match self.target {
+                Some(target) => session.start_connect(target),
+                None => session.reply = true,
+            }
+            session.addr = Some(addr);
+            session.token = token;
+            sessions.insert(key, session);
+        }
+        let session = sessions.get_mut(&key).unwrap();
+        if session.token != token {
+            return;
+        }
+        if session.closing(data, clock()) {
+            return;
+        }
+        if let Some(nonce) = control_nonce(data, CMD_PATH_RESPONSE) {
+            if session.challenge == Some((addr, nonce)) {
+                session.addr = Some(addr);
+                session.challenge = None;
+            }
+            return;
+        }
+        if !session.input(data, clock()) || session.addr == Some(addr) {
+            return;
+        }
+        if !self.config.validate_path {
+            session.addr = Some(addr);
+            return;
+        }
+        // challenge again for every packet, the challenge may be lost
+        let nonce = match session.challenge {
+            Some((challenged, nonce)) if challenged == addr => nonce,
+            _ => rand::random::<u64>(),
+        };
+        session.challenge = Some((addr, nonce));
+        let _ = self.udp.send_to(&control(conv, CMD_PATH_CHALLENGE, nonce), addr);
+    }
+}
@@ -2,4 +2,23 @@ extern crate kcp;
 extern crate fixbuf;
 extern crate time;
 extern crate rand;
+#[cfg(feature = "serde")]
+extern crate bincode;
+#[cfg(feature = "async")]
+extern crate futures_io;
+#[cfg(feature = "reactor")]
+extern crate mio;
+mod util;
+// test() and its network simulator are ported as is from the C test
+#[allow(non_camel_case_types, overflowing_literals, unused_imports, unused_mut, unused_must_use,
+        clippy::identity_op, clippy::needless_late_init, clippy::needless_return,
+        clippy::needless_borrow, clippy::unnecessary_cast)]
 mod test_kcp;
+mod test_capi;
+mod test_batch;
+#[cfg(feature = "reactor")]
+mod test_reactor;
+mod test_scheduler;
+mod test_tunnel;
+mod test_socks5;
+mod test_smux;
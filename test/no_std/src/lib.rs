@@ -0,0 +1,26 @@
+//! Builds the protocol core without std, e.g. for a Cortex-M4:
+//!
+//!     rustup target add thumbv7em-none-eabihf
+//!     cargo build --manifest-path test/no_std/Cargo.toml --target thumbv7em-none-eabihf
+#![no_std]
+extern crate alloc;
+extern crate kcp;
+
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use kcp::{ByteBuffer, KCP};
+
+/// send `data` over a session whose packets end up in the returned vec
+pub fn send_one(conv: u32, data: &[u8], current: u32) -> Rc<RefCell<Vec<Vec<u8>>>> {
+    let packets = Rc::new(RefCell::new(Vec::new()));
+    let out = packets.clone();
+    let mut kcp = KCP::new(conv, move |buf: &mut ByteBuffer, size: usize| {
+        out.borrow_mut().push(buf.to_bytes()[..size].to_vec());
+    });
+    let mut buf = ByteBuffer::with_capacity(data.len());
+    buf.write_bytes(data).unwrap();
+    kcp.send(&mut buf);
+    kcp.update(current);
+    packets
+}
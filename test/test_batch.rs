@@ -0,0 +1,77 @@
+use std::net::UdpSocket;
+use std::time::Duration;
+use kcp::batch::{RecvBatch, SendBatch};
+
+fn bind() -> UdpSocket {
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    udp.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    udp
+}
+
+#[test]
+fn test_batch() {
+    let (sender, receiver1, receiver2) = (bind(), bind(), bind());
+    let addr1 = receiver1.local_addr().unwrap();
+    let addr2 = receiver2.local_addr().unwrap();
+    let mut batch = SendBatch::new();
+    for i in 0..100 {
+        let addr = if i % 2 == 0 { addr1 } else { addr2 };
+        batch.push(vec![i as u8; 100 + i], Some(addr));
+    }
+    assert_eq!(batch.send(&sender), 100);
+    assert!(batch.is_empty());
+
+    let mut received = RecvBatch::new(16, 2048);
+    for &(receiver, first) in &[(&receiver1, 0), (&receiver2, 1)] {
+        let mut expected = first;
+        while expected < 100 {
+            let n = received.recv(receiver).unwrap();
+            assert!(n > 0 && n <= 16);
+            for i in 0..n {
+                let (data, from) = received.get(i);
+                assert_eq!(from, sender.local_addr().unwrap());
+                assert!(data == &vec![expected as u8; 100 + expected][..]);
+                expected += 2;
+            }
+        }
+    }
+    assert!(received.recv(&receiver1).is_err());
+
+    // a connected socket needs no address
+    sender.connect(addr1).unwrap();
+    batch.push(vec![1, 2, 3], None);
+    assert_eq!(batch.send(&sender), 1);
+    assert_eq!(received.recv(&receiver1).unwrap(), 1);
+    assert_eq!(received.get(0).0, &[1, 2, 3]);
+}
+
+#[test]
+fn test_offload() {
+    let (sender, receiver) = (bind(), bind());
+    let addr = receiver.local_addr().unwrap();
+    let mut batch = SendBatch::new();
+    let mut received = RecvBatch::new(4, 2048);
+    // without kernel support both fall back to plain datagrams
+    let gso = batch.enable_gso(&sender);
+    received.enable_gro(&receiver);
+    let mut sent = Vec::new();
+    for i in 0..100 {
+        sent.push(vec![i as u8; 1400]);
+    }
+    sent.push(vec![100; 300]);
+    sent.push(vec![101; 1400]);
+    for packet in &sent {
+        batch.push(packet.clone(), Some(addr));
+    }
+    assert_eq!(batch.send(&sender), sent.len());
+    assert_eq!(batch.gso(), gso);
+
+    let mut packets = Vec::new();
+    while packets.len() < sent.len() {
+        let n = received.recv(&receiver).unwrap();
+        for i in 0..n {
+            packets.push(received.get(i).0.to_vec());
+        }
+    }
+    assert!(packets == sent);
+}
@@ -0,0 +1,114 @@
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::slice;
+use kcp::capi::*;
+
+struct Link {
+    packets: Vec<Vec<u8>>,
+}
+
+extern "C" fn output(buf: *const c_char, len: c_int, _kcp: *mut IKCPCB, user: *mut c_void) -> c_int {
+    let link = unsafe { &mut *(user as *mut Link) };
+    let data = unsafe { slice::from_raw_parts(buf as *const u8, len as usize) };
+    link.packets.push(data.to_vec());
+    0
+}
+
+#[test]
+fn test_capi_roundtrip() {
+    let conv = 0x11223344;
+    let mut link = Link { packets: Vec::new() };
+    unsafe {
+        let kcp1 = ikcp_create(conv, &mut link as *mut Link as *mut c_void);
+        let kcp2 = ikcp_create(conv, ptr::null_mut());
+        ikcp_setoutput(kcp1, Some(output));
+        ikcp_nodelay(kcp1, 1, 10, 2, 1);
+        assert_eq!(ikcp_setmtu(kcp1, 10), -1);
+
+        let msg = b"hello kcp";
+        assert_eq!(ikcp_send(kcp1, msg.as_ptr() as *const c_char, msg.len() as c_int), 0);
+        assert_eq!(ikcp_waitsnd(kcp1), 1);
+        ikcp_update(kcp1, 100);
+        ikcp_flush(kcp1);
+        assert!(!link.packets.is_empty());
+
+        for packet in &link.packets {
+            assert_eq!(ikcp_getconv(packet.as_ptr() as *const c_void), conv);
+            ikcp_input(kcp2, packet.as_ptr() as *const c_char, packet.len() as _);
+        }
+        assert_eq!(ikcp_peeksize(kcp2), msg.len() as c_int);
+        let mut small = [0u8; 4];
+        assert_eq!(ikcp_recv(kcp2, small.as_mut_ptr() as *mut c_char, 4), -3);
+        let mut buf = [0u8; 64];
+        // a negative len peeks and leaves the message queued
+        assert_eq!(ikcp_recv(kcp2, small.as_mut_ptr() as *mut c_char, -4), -3);
+        let n = ikcp_recv(kcp2, buf.as_mut_ptr() as *mut c_char, -(buf.len() as c_int));
+        assert_eq!(&buf[..n as usize], msg);
+        assert_eq!(ikcp_peeksize(kcp2), msg.len() as c_int);
+        // the peek copies the message as it is, whatever room is claimed
+        assert_eq!(ikcp_recv(kcp2, buf.as_mut_ptr() as *mut c_char, c_int::MIN), msg.len() as c_int);
+        buf = [0u8; 64];
+        let n = ikcp_recv(kcp2, buf.as_mut_ptr() as *mut c_char, buf.len() as c_int);
+        assert_eq!(&buf[..n as usize], msg);
+        assert_eq!(ikcp_recv(kcp2, buf.as_mut_ptr() as *mut c_char, buf.len() as c_int), -1);
+
+        ikcp_release(kcp1);
+        ikcp_release(kcp2);
+    }
+}
+
+#[test]
+fn test_capi_null() {
+    unsafe {
+        let kcp = ikcp_create(1, ptr::null_mut());
+        assert_eq!(ikcp_send(kcp, ptr::null(), 4), -1);
+        assert_eq!(ikcp_input(kcp, ptr::null(), 24), -1);
+        assert_eq!(ikcp_getconv(ptr::null()), 0);
+        ikcp_release(kcp);
+
+        let null = ptr::null_mut();
+        assert_eq!(ikcp_send(null, b"x".as_ptr() as *const c_char, 1), -1);
+        assert_eq!(ikcp_recv(null, ptr::null_mut(), 16), -1);
+        assert_eq!(ikcp_waitsnd(null), -1);
+        assert_eq!(ikcp_check(null, 7), 7);
+        ikcp_update(null, 100);
+        ikcp_flush(null);
+        ikcp_release(null);
+    }
+}
+
+/// name and parameter count of every `ikcp_*` function in `text`
+fn functions(text: &str, marker: &str) -> Vec<(String, usize)> {
+    let mut found = Vec::new();
+    for (start, _) in text.match_indices(marker) {
+        let rest = &text[start + marker.len()..];
+        let name_len = rest.find('(').unwrap();
+        let (mut depth, mut params, mut empty) = (0, 1, true);
+        for c in rest[name_len..].chars() {
+            match c {
+                '(' => depth += 1,
+                ')' if depth == 1 => break,
+                ')' => depth -= 1,
+                ',' if depth == 1 => params += 1,
+                c if depth == 1 && !c.is_whitespace() => empty = false,
+                _ => {}
+            }
+        }
+        found.push((rest[..name_len].to_string(), if empty { 0 } else { params }));
+    }
+    found.sort();
+    found
+}
+
+#[test]
+fn test_header() {
+    let header = include_str!("../include/ikcp.h");
+    let source = include_str!("../src/capi.rs");
+    // drop the comments, which mention calls like ikcp_nodelay(kcp, ...)
+    let declarations: String = header.split("/*")
+        .map(|part| part.splitn(2, "*/").last().unwrap())
+        .collect();
+    let exported = functions(source, "extern \"C\" fn ikcp_");
+    assert_eq!(exported.len(), 15);
+    assert_eq!(functions(&declarations, " ikcp_"), exported);
+}
@@ -5,9 +5,13 @@ use time;
 use rand;
 use rand::Rng;
 use std::vec::Vec;
-use kcp::KCP;
+use kcp::{AckPolicy, BufferPool, Bytes, ERR_WOULD_BLOCK, KCP, KcpSnapshot, Pacing, RateLimiter};
+#[cfg(feature = "serde")]
+use bincode;
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::cmp::max;
 
 fn iclock() -> i32 {
     let (s, u): (i64, i64);
@@ -215,9 +219,1016 @@ fn test(mode: isize) {
     println!("avgrtt = {} max rtt = {}", (sumrtt / count), maxrtt);
 }
 
+/// append a segment as the peer would encode it
+fn encode(buf: &mut ByteBuffer, cmd: u8, sn: u32, una: u32, data: &[u8]) {
+    buf.write_u32(1).unwrap();
+    buf.write_u8(cmd).unwrap();
+    buf.write_u8(0).unwrap();
+    buf.write_u16(128).unwrap();
+    buf.write_u32(0).unwrap();
+    buf.write_u32(sn).unwrap();
+    buf.write_u32(una).unwrap();
+    buf.write_u32(data.len() as u32).unwrap();
+    buf.write_bytes(data).unwrap();
+}
+
+fn input_push(kcp: &mut KCP, segments: &[(u32, &[u8])]) {
+    let mut buf = ByteBuffer::with_capacity(2000);
+    for &(sn, data) in segments {
+        encode(&mut buf, 81, sn, 0, data);
+    }
+    assert_eq!(kcp.input(&mut buf), 0);
+}
+
+fn recv_message(kcp: &mut KCP) -> Option<Vec<u8>> {
+    let mut buf = ByteBuffer::with_capacity(2000);
+    if kcp.recv(&mut buf) < 0 {
+        return None;
+    }
+    Some(buf.to_bytes())
+}
+
+#[test]
+fn test_recv_out_of_order() {
+    let mut kcp = KCP::new(1, |_, _| {});
+    input_push(&mut kcp, &[(1, b"b")]);
+    assert_eq!(recv_message(&mut kcp), None);
+    input_push(&mut kcp, &[(0, b"a")]);
+    assert_eq!(recv_message(&mut kcp), Some(b"a".to_vec()));
+    assert_eq!(recv_message(&mut kcp), Some(b"b".to_vec()));
+}
+
+#[test]
+fn test_input_duplicate() {
+    let mut kcp = KCP::new(1, |_, _| {});
+    input_push(&mut kcp, &[(0, b"a")]);
+    assert_eq!(recv_message(&mut kcp), Some(b"a".to_vec()));
+    // the payload of the repeated segment is skipped, not parsed as a header
+    input_push(&mut kcp, &[(0, b"a"), (1, b"b")]);
+    assert_eq!(recv_message(&mut kcp), Some(b"b".to_vec()));
+}
+
+#[test]
+fn test_send_read_remain() {
+    let mut kcp = KCP::new(1, |_, _| {});
+    let mut buf = ByteBuffer::with_capacity(2000);
+    assert!(kcp.send(&mut buf) < 0);
+    buf.write_bytes(b"skip, send").unwrap();
+    buf.read_bytes(6).unwrap();
+    assert_eq!(kcp.send(&mut buf), 0);
+    assert_eq!(kcp.wait_snd(), 1);
+}
+
+#[test]
+fn test_flush_window() {
+    let mut kcp = KCP::new(1, |_, _| {});
+    kcp.no_delay(1, 10, 2, 1);
+    kcp.wnd_size(1, 128);
+    for _ in 0..3 {
+        let mut buf = ByteBuffer::with_capacity(2000);
+        buf.write_bytes(b"data").unwrap();
+        kcp.send(&mut buf);
+    }
+    kcp.update(0);
+    // the segments beyond the window stay queued
+    assert_eq!(kcp.wait_snd(), 3);
+}
+
+#[test]
+fn test_input_una() {
+    let mut kcp = KCP::new(1, |_, _| {});
+    kcp.no_delay(1, 10, 2, 1);
+    for _ in 0..3 {
+        let mut buf = ByteBuffer::with_capacity(2000);
+        buf.write_bytes(b"data").unwrap();
+        kcp.send(&mut buf);
+    }
+    kcp.update(0);
+    let mut buf = ByteBuffer::with_capacity(2000);
+    encode(&mut buf, 82, 2, 3, &[]);
+    assert_eq!(kcp.input(&mut buf), 0);
+    assert_eq!(kcp.wait_snd(), 0);
+}
+
 #[test]
 fn test_network() {
     test(0);
     test(1);
     test(2);
 }
+
+/// packets written by one endpoint, waiting to be delivered to the other
+type Wire = Rc<RefCell<VecDeque<Vec<u8>>>>;
+
+fn endpoint(conv: u32) -> (KCP, Wire) {
+    let wire: Wire = Rc::new(RefCell::new(VecDeque::new()));
+    let out = wire.clone();
+    let kcp = KCP::new(conv, move |buf: &mut ByteBuffer, size: usize| {
+        out.borrow_mut().push_back(buf.to_bytes()[..size].to_vec());
+    });
+    (kcp, wire)
+}
+
+/// feed every packet on the wire to kcp, returns the bytes delivered
+fn deliver(wire: &Wire, kcp: &mut KCP) -> usize {
+    let mut bytes = 0;
+    while let Some(packet) = wire.borrow_mut().pop_front() {
+        bytes += packet.len();
+        let mut buf = ByteBuffer::with_capacity(packet.len());
+        buf.write_bytes(&packet).unwrap();
+        kcp.input(&mut buf);
+    }
+    bytes
+}
+
+fn recv_all(kcp: &mut KCP) -> usize {
+    let mut count = 0;
+    while kcp.peek_size() > 0 {
+        let mut buf = ByteBuffer::with_capacity(kcp.peek_size() as usize);
+        assert!(kcp.recv(&mut buf) > 0);
+        count += 1;
+    }
+    count
+}
+
+/// send 64 messages from kcp1 to kcp2 losing the first packet, returns
+/// the number of messages received and the bytes kcp2 sent back
+fn sack_transfer(sack1: bool, sack2: bool) -> (usize, usize) {
+    let (mut kcp1, wire1) = endpoint(1);
+    let (mut kcp2, wire2) = endpoint(1);
+    kcp1.set_sack(sack1);
+    kcp2.set_sack(sack2);
+    for kcp in &mut [&mut kcp1, &mut kcp2] {
+        kcp.wnd_size(128, 128);
+        kcp.no_delay(1, 10, 2, 1);
+    }
+    // a first exchange lets both sides learn what the other supports
+    let mut current = 0;
+    let mut buf = ByteBuffer::with_capacity(8);
+    buf.write_u32(0).unwrap();
+    kcp1.send(&mut buf);
+    for _ in 0..2 {
+        current += 10;
+        kcp1.update(current);
+        deliver(&wire1, &mut kcp2);
+        kcp2.update(current);
+        deliver(&wire2, &mut kcp1);
+    }
+    let mut received = recv_all(&mut kcp2);
+
+    for _ in 0..64 {
+        let mut buf = ByteBuffer::with_capacity(1000);
+        buf.write_bytes(&[0u8; 1000]).unwrap();
+        kcp1.send(&mut buf);
+    }
+    current += 10;
+    kcp1.update(current);
+    wire1.borrow_mut().pop_front();
+    deliver(&wire1, &mut kcp2);
+    current += 10;
+    kcp2.update(current);
+    let ack_bytes = deliver(&wire2, &mut kcp1);
+    for _ in 0..100 {
+        current += 10;
+        kcp1.update(current);
+        deliver(&wire1, &mut kcp2);
+        kcp2.update(current);
+        deliver(&wire2, &mut kcp1);
+        received += recv_all(&mut kcp2);
+    }
+    (received, ack_bytes)
+}
+
+#[test]
+fn test_sack() {
+    let (received, legacy_bytes) = sack_transfer(false, false);
+    assert_eq!(received, 65);
+    let (received, sack_bytes) = sack_transfer(true, true);
+    assert_eq!(received, 65);
+    assert!(sack_bytes * 10 < legacy_bytes);
+    // a peer without SACK keeps getting plain acks
+    assert_eq!(sack_transfer(true, false), (65, legacy_bytes));
+    assert_eq!(sack_transfer(false, true), (65, legacy_bytes));
+}
+
+#[test]
+fn test_sack_bounds() {
+    let (mut kcp, _wire) = endpoint(1);
+    kcp.set_sack(true);
+    kcp.no_delay(1, 10, 2, 1);
+    send_bytes(&mut kcp, 10);
+    kcp.update(0);
+    // bits outside snd_una..snd_nxt ack nothing, the sn wraps around
+    let mut buf = ByteBuffer::with_capacity(100);
+    encode(&mut buf, 85, 0xffff_fff0, 0, &[0xff; 2]);
+    assert_eq!(kcp.input(&mut buf), 0);
+    assert_eq!(kcp.wait_snd(), 1);
+    let mut buf = ByteBuffer::with_capacity(100);
+    encode(&mut buf, 85, 0xffff_fff8, 0, &[0xff, 0x01]);
+    assert_eq!(kcp.input(&mut buf), 0);
+    assert_eq!(kcp.wait_snd(), 0);
+}
+
+#[test]
+fn test_sack_flag() {
+    let (mut kcp, wire) = endpoint(1);
+    kcp.set_sack(true);
+    kcp.update(0);
+    // frg of an MTU probe is no SACK flag
+    let mut buf = ByteBuffer::with_capacity(100);
+    encode(&mut buf, 87, 0, 0, &[]);
+    let mut probe = buf.to_bytes();
+    probe[5] = 1;
+    kcp.input_slice(&probe);
+    input_push(&mut kcp, &[(0, b"x")]);
+    wire.borrow_mut().clear();
+    kcp.flush();
+    assert!(wire.borrow().iter().all(|packet| packet[4] != 85));
+    assert!(wire.borrow().iter().any(|packet| packet[4] == 82));
+}
+
+fn send_bytes(kcp: &mut KCP, len: usize) {
+    let mut buf = ByteBuffer::with_capacity(len);
+    buf.write_bytes(&vec![0u8; len]).unwrap();
+    kcp.send(&mut buf);
+}
+
+#[test]
+fn test_ack_policy() {
+    // kcp1 sends one segment per packet, held back on the wire
+    let (mut kcp1, wire1) = endpoint(1);
+    kcp1.no_delay(1, 10, 2, 1);
+    for _ in 0..6 {
+        send_bytes(&mut kcp1, 1000);
+    }
+    kcp1.update(10);
+    let packets: Vec<Vec<u8>> = wire1.borrow_mut().drain(..).collect();
+    assert_eq!(packets.len(), 6);
+    let input = |kcp: &mut KCP, packet: &Vec<u8>| {
+        let mut buf = ByteBuffer::with_capacity(packet.len());
+        buf.write_bytes(packet).unwrap();
+        kcp.input(&mut buf);
+    };
+
+    let (mut kcp2, wire2) = endpoint(1);
+    kcp2.no_delay(1, 10, 2, 1);
+    kcp2.update(10);
+    input(&mut kcp2, &packets[0]);
+    assert!(wire2.borrow().is_empty());
+    kcp2.update(20);
+    assert_eq!(wire2.borrow_mut().drain(..).count(), 1);
+
+    let (mut kcp2, wire2) = endpoint(1);
+    kcp2.set_ack_policy(AckPolicy::NoDelay);
+    kcp2.update(10);
+    input(&mut kcp2, &packets[0]);
+    assert_eq!(wire2.borrow_mut().drain(..).count(), 1);
+
+    let (mut kcp2, wire2) = endpoint(1);
+    kcp2.no_delay(1, 10, 2, 1);
+    kcp2.set_ack_policy(AckPolicy::Delayed {
+        max_delay: 5,
+        every: 4,
+    });
+    kcp2.update(10);
+    for packet in &packets[..3] {
+        input(&mut kcp2, packet);
+    }
+    assert!(wire2.borrow().is_empty());
+    input(&mut kcp2, &packets[3]);
+    assert_eq!(wire2.borrow_mut().drain(..).count(), 1);
+    input(&mut kcp2, &packets[4]);
+    assert_eq!(kcp2.check(12), 15);
+    kcp2.update(14);
+    assert!(wire2.borrow().is_empty());
+    kcp2.update(15);
+    assert_eq!(wire2.borrow_mut().drain(..).count(), 1);
+}
+
+#[test]
+fn test_ack_piggyback() {
+    let reply_bytes = |piggyback: bool| {
+        let (mut kcp1, wire1) = endpoint(1);
+        let (mut kcp2, wire2) = endpoint(1);
+        kcp1.no_delay(0, 100, 0, 1);
+        kcp2.set_ack_piggyback(piggyback);
+        for _ in 0..4 {
+            send_bytes(&mut kcp1, 100);
+        }
+        kcp1.update(100);
+        kcp2.update(100);
+        deliver(&wire1, &mut kcp2);
+        send_bytes(&mut kcp2, 100);
+        kcp2.update(200);
+        let bytes = wire2.borrow().iter().map(|p| p.len()).sum::<usize>();
+        deliver(&wire2, &mut kcp1);
+        assert_eq!(kcp1.wait_snd(), 0);
+        bytes
+    };
+    assert_eq!(reply_bytes(false), reply_bytes(true) + 4 * 24);
+
+    // a reply the pacer or a rate limiter holds back does not take the
+    // acks with it
+    held_back_reply(|kcp| kcp.set_pacing(Pacing::Rate(1000)));
+    held_back_reply(|kcp| kcp.set_rate_limit(100, 1100));
+}
+
+/// let `hold` slow kcp2 down so a reply after its first message waits
+fn held_back_reply<F: Fn(&mut KCP)>(hold: F) {
+    let (mut kcp1, wire1) = endpoint(1);
+    let (mut kcp2, wire2) = endpoint(1);
+    kcp1.no_delay(0, 100, 0, 1);
+    kcp2.set_ack_piggyback(true);
+    hold(&mut kcp2);
+    kcp1.update(100);
+    kcp2.update(100);
+    send_bytes(&mut kcp2, 1000);
+    kcp2.update(200);
+    deliver(&wire2, &mut kcp1);
+    for _ in 0..4 {
+        send_bytes(&mut kcp1, 100);
+    }
+    kcp1.update(200);
+    deliver(&wire1, &mut kcp2);
+    send_bytes(&mut kcp2, 100);
+    kcp2.update(300);
+    deliver(&wire2, &mut kcp1);
+    assert_eq!(kcp1.wait_snd(), 0);
+    assert_eq!(kcp2.wait_snd(), 1);
+}
+
+/// run both endpoints from `current` until kcp2 received `count` messages
+fn transfer(kcp1: &mut KCP,
+            wire1: &Wire,
+            kcp2: &mut KCP,
+            wire2: &Wire,
+            current: &mut u32,
+            count: usize)
+            -> Vec<Vec<u8>> {
+    let mut messages = Vec::new();
+    let deadline = *current + 100000;
+    while messages.len() < count && *current < deadline {
+        *current += 10;
+        kcp1.update(*current);
+        deliver(wire1, kcp2);
+        kcp2.update(*current);
+        deliver(wire2, kcp1);
+        while kcp2.peek_size() > 0 {
+            let size = kcp2.peek_size() as usize;
+            let mut buf = ByteBuffer::with_capacity(size);
+            assert_eq!(kcp2.recv(&mut buf), size as isize);
+            messages.push(buf.to_bytes()[..size].to_vec());
+        }
+    }
+    messages
+}
+
+#[test]
+fn test_large_message() {
+    let large: Vec<u8> = (0..600000).map(|i| (i % 251) as u8).collect();
+    let (mut kcp1, wire1) = endpoint(1);
+    let (mut kcp2, wire2) = endpoint(1);
+    let mut buf = ByteBuffer::with_capacity(large.len());
+    buf.write_bytes(&large).unwrap();
+    assert_eq!(kcp1.send(&mut buf), -2);
+
+    kcp1.set_max_message_size(1 << 20);
+    kcp2.set_max_message_size(1 << 20);
+    kcp1.no_delay(1, 10, 2, 1);
+    let mut buf = ByteBuffer::with_capacity(large.len());
+    buf.write_bytes(&large).unwrap();
+    assert_eq!(kcp1.send(&mut buf), 0);
+    send_bytes(&mut kcp1, 10);
+    let mut current = 0;
+    let messages = transfer(&mut kcp1, &wire1, &mut kcp2, &wire2, &mut current, 2);
+    assert_eq!(messages.len(), 2);
+    assert!(messages[0] == large);
+    assert_eq!(messages[1].len(), 10);
+
+    // the receiver drops messages above its own limit
+    kcp2.set_max_message_size(100000);
+    let mut buf = ByteBuffer::with_capacity(large.len());
+    buf.write_bytes(&large).unwrap();
+    assert_eq!(kcp1.send(&mut buf), 0);
+    send_bytes(&mut kcp1, 20);
+    let messages = transfer(&mut kcp1, &wire1, &mut kcp2, &wire2, &mut current, 1);
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].len(), 20);
+}
+
+#[test]
+fn test_mtu_discovery() {
+    let (mut kcp1, wire1) = endpoint(1);
+    let (mut kcp2, wire2) = endpoint(1);
+    assert_eq!(kcp1.set_mtu_discovery(1400, 1200), -1);
+    assert_eq!(kcp1.set_mtu_discovery(1200, 1472), 0);
+    assert_eq!(kcp1.mtu(), 1200);
+    kcp1.no_delay(1, 10, 2, 1);
+    let message: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+    for _ in 0..200 {
+        let mut buf = ByteBuffer::with_capacity(message.len());
+        buf.write_bytes(&message).unwrap();
+        assert_eq!(kcp1.send(&mut buf), 0);
+    }
+
+    // the path drops packets above 1300 bytes
+    let mut received = 0;
+    let mut current = 0;
+    while current < 60000 {
+        current += 10;
+        kcp1.update(current);
+        while let Some(packet) = wire1.borrow_mut().pop_front() {
+            if packet.len() <= 1300 {
+                let mut buf = ByteBuffer::with_capacity(packet.len());
+                buf.write_bytes(&packet).unwrap();
+                kcp2.input(&mut buf);
+            }
+        }
+        kcp2.update(current);
+        deliver(&wire2, &mut kcp1);
+        while kcp2.peek_size() > 0 {
+            let mut buf = ByteBuffer::with_capacity(message.len());
+            assert_eq!(kcp2.recv(&mut buf), message.len() as isize);
+            assert!(buf.to_bytes()[..message.len()] == message[..]);
+            received += 1;
+        }
+    }
+    assert_eq!(received, 200);
+    assert_eq!(kcp1.mtu(), 1300);
+}
+
+#[test]
+fn test_stream_resegment() {
+    let (mut kcp1, wire1) = endpoint(1);
+    let (mut kcp2, wire2) = endpoint(1);
+    kcp1.set_stream(true);
+    kcp2.set_stream(true);
+    kcp1.no_delay(1, 10, 2, 1);
+    kcp1.wnd_size(128, 128);
+    kcp2.wnd_size(128, 128);
+    let data: Vec<u8> = (0..400 * 1376).map(|i| (i % 251) as u8).collect();
+    for chunk in data.chunks(200 * 1376) {
+        let mut buf = ByteBuffer::with_capacity(chunk.len());
+        buf.write_bytes(chunk).unwrap();
+        assert_eq!(kcp1.send(&mut buf), 0);
+    }
+    // more than 255 segments of the smaller size are queued now
+    assert_eq!(kcp1.set_mtu(1000), 0);
+    let mut received = Vec::new();
+    let mut current = 0;
+    while received.len() < data.len() && current < 100000 {
+        current += 10;
+        kcp1.update(current);
+        deliver(&wire1, &mut kcp2);
+        kcp2.update(current);
+        deliver(&wire2, &mut kcp1);
+        while kcp2.peek_size() > 0 {
+            let size = kcp2.peek_size() as usize;
+            let mut buf = ByteBuffer::with_capacity(size);
+            assert_eq!(kcp2.recv(&mut buf), size as isize);
+            received.extend_from_slice(&buf.to_bytes()[..size]);
+        }
+    }
+    assert!(received == data);
+}
+
+#[test]
+fn test_mtu_black_hole() {
+    let (mut kcp1, wire1) = endpoint(1);
+    let (mut kcp2, wire2) = endpoint(1);
+    assert_eq!(kcp1.set_mtu_discovery(600, 1472), 0);
+    kcp1.no_delay(1, 10, 2, 1);
+    let message: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+    let mut received = 0;
+    let mut current = 0;
+    // the path carries every size at first, then drops packets above
+    // 1000 bytes
+    for &(limit, until) in &[(1472, 2000), (1000, 60000)] {
+        while current < until {
+            current += 10;
+            if kcp1.wait_snd() < 64 {
+                let mut buf = ByteBuffer::with_capacity(message.len());
+                buf.write_bytes(&message).unwrap();
+                assert_eq!(kcp1.send(&mut buf), 0);
+            }
+            kcp1.update(current);
+            while let Some(packet) = wire1.borrow_mut().pop_front() {
+                if packet.len() <= limit {
+                    let mut buf = ByteBuffer::with_capacity(packet.len());
+                    buf.write_bytes(&packet).unwrap();
+                    kcp2.input(&mut buf);
+                }
+            }
+            kcp2.update(current);
+            deliver(&wire2, &mut kcp1);
+            while kcp2.peek_size() > 0 {
+                let mut buf = ByteBuffer::with_capacity(message.len());
+                assert_eq!(kcp2.recv(&mut buf), message.len() as isize);
+                assert!(buf.to_bytes()[..message.len()] == message[..]);
+                received += 1;
+            }
+        }
+        assert_eq!(kcp1.mtu(), limit as u32);
+    }
+    // still moving after the fall back
+    let before = received;
+    for _ in 0..1000 {
+        current += 10;
+        kcp1.update(current);
+        deliver(&wire1, &mut kcp2);
+        kcp2.update(current);
+        deliver(&wire2, &mut kcp1);
+        received += recv_all(&mut kcp2);
+    }
+    assert!(received > before);
+    assert_eq!(kcp1.wait_snd(), 0);
+}
+
+#[test]
+fn test_pacing() {
+    let (mut kcp1, wire1) = endpoint(1);
+    let (mut kcp2, wire2) = endpoint(1);
+    kcp1.no_delay(1, 10, 2, 1);
+    kcp1.wnd_size(128, 128);
+    kcp1.set_pacing(Pacing::Rate(100000));
+    for _ in 0..100 {
+        send_bytes(&mut kcp1, 1000);
+    }
+
+    // no update sends more than the burst, and check wakes us in between
+    let mut current = 0;
+    let mut received = 0;
+    while received < 100 && current < 10000 {
+        kcp1.update(current);
+        let burst: usize = wire1.borrow().iter().map(|packet| packet.len()).sum();
+        assert!(burst <= 2 * 1400 + 1024);
+        deliver(&wire1, &mut kcp2);
+        kcp2.update(current);
+        deliver(&wire2, &mut kcp1);
+        received += recv_all(&mut kcp2);
+        let next = kcp1.check(current);
+        assert!(next <= current + 10);
+        current = max(next, current + 1);
+    }
+    assert_eq!(received, 100);
+    assert!((950..1200).contains(&current));
+}
+
+#[test]
+fn test_rate_limit() {
+    let shared = RateLimiter::new(50000, 5000);
+    let mut senders = Vec::new();
+    let mut receivers = Vec::new();
+    for conv in 0..2 {
+        let (mut kcp1, wire1) = endpoint(conv);
+        let (kcp2, wire2) = endpoint(conv);
+        kcp1.no_delay(1, 10, 2, 1);
+        kcp1.wnd_size(128, 128);
+        kcp1.set_shared_limiter(Some(shared.clone()));
+        for _ in 0..50 {
+            send_bytes(&mut kcp1, 1000);
+        }
+        senders.push((kcp1, wire1));
+        receivers.push((kcp2, wire2));
+    }
+    // the second session is also held to its own, lower rate
+    senders[1].0.set_rate_limit(10000, 2000);
+
+    let mut done = [0; 2];
+    let mut received = [0; 2];
+    let mut current = 0;
+    while received[1] < 50 && current < 20000 {
+        current += 10;
+        for i in 0..2 {
+            let (ref mut kcp1, ref wire1) = senders[i];
+            let (ref mut kcp2, ref wire2) = receivers[i];
+            kcp1.update(current);
+            deliver(wire1, kcp2);
+            kcp2.update(current);
+            deliver(wire2, kcp1);
+            received[i] += recv_all(kcp2);
+            if received[i] == 50 && done[i] == 0 {
+                done[i] = current;
+            }
+        }
+    }
+    assert_eq!(received, [50, 50]);
+    // 50 KB at 10 KB/s, less while the first session takes most of 50 KB/s
+    assert!(done[1] >= 4500 && done[1] < 7000);
+    assert!(done[0] >= 900 && done[0] < 1500);
+    assert!(senders[1].0.throttled_time() > 4000);
+    assert!(shared.throttled_time() > 0);
+}
+
+#[test]
+fn test_send_watermarks() {
+    let (mut kcp1, wire1) = endpoint(1);
+    let (mut kcp2, wire2) = endpoint(1);
+    kcp1.no_delay(1, 10, 2, 1);
+    kcp1.set_send_watermarks(10000, 2000);
+    let writable = Rc::new(RefCell::new(0));
+    let counter = writable.clone();
+    kcp1.set_writable_callback(move || *counter.borrow_mut() += 1);
+
+    let mut sent = 0;
+    loop {
+        let mut buf = ByteBuffer::with_capacity(1000);
+        buf.write_bytes(&[0; 1000]).unwrap();
+        let ret = kcp1.send(&mut buf);
+        if ret == ERR_WOULD_BLOCK {
+            break;
+        }
+        assert_eq!(ret, 0);
+        sent += 1;
+    }
+    assert_eq!(sent, 10);
+    assert_eq!(kcp1.wait_snd_bytes(), 10000);
+    assert!(!kcp1.is_writable());
+
+    let mut current = 0;
+    let messages = transfer(&mut kcp1, &wire1, &mut kcp2, &wire2, &mut current, 10);
+    assert_eq!(messages.len(), 10);
+    assert_eq!(*writable.borrow(), 1);
+    assert!(kcp1.is_writable());
+
+    // segments count as well
+    kcp1.set_send_watermarks(0, 0);
+    kcp1.set_send_segment_watermarks(2, 0);
+    send_bytes(&mut kcp1, 3000);
+    assert_eq!(kcp1.wait_snd(), 3);
+    assert!(!kcp1.is_writable());
+    let mut buf = ByteBuffer::with_capacity(1);
+    buf.write_u8(0).unwrap();
+    assert_eq!(kcp1.send(&mut buf), ERR_WOULD_BLOCK);
+    // changing the watermarks can unblock the sender too
+    kcp1.set_send_segment_watermarks(8, 4);
+    assert_eq!(*writable.borrow(), 2);
+
+    // expired data that never got through makes room as well
+    let (mut kcp3, _wire3) = endpoint(1);
+    kcp3.set_send_watermarks(3000, 0);
+    let writable = Rc::new(RefCell::new(0));
+    let counter = writable.clone();
+    kcp3.set_writable_callback(move || *counter.borrow_mut() += 1);
+    for _ in 0..3 {
+        let mut buf = ByteBuffer::with_capacity(1000);
+        buf.write_bytes(&[0; 1000]).unwrap();
+        assert_eq!(kcp3.send_with_deadline(&mut buf, 100), 0);
+    }
+    let mut buf = ByteBuffer::with_capacity(1000);
+    buf.write_bytes(&[0; 1000]).unwrap();
+    assert_eq!(kcp3.send_with_deadline(&mut buf, 100), ERR_WOULD_BLOCK);
+    kcp3.update(10);
+    assert_eq!(*writable.borrow(), 0);
+    kcp3.update(200);
+    assert_eq!(*writable.borrow(), 1);
+    assert!(kcp3.is_writable());
+}
+
+#[test]
+fn test_send_with_deadline() {
+    let (mut kcp1, wire1) = endpoint(1);
+    let (mut kcp2, wire2) = endpoint(1);
+    kcp1.no_delay(1, 10, 2, 1);
+    kcp1.update(0);
+    kcp2.update(0);
+    // a 3 fragment message of which only the second gets through in time
+    let mut buf = ByteBuffer::with_capacity(3000);
+    buf.write_bytes(&[1; 3000]).unwrap();
+    assert_eq!(kcp1.send_with_deadline(&mut buf, 100), 0);
+    send_bytes(&mut kcp1, 10);
+
+    let mut messages = Vec::new();
+    let mut packets = 0;
+    let mut current = 0;
+    while current < 1000 {
+        current += 10;
+        kcp1.update(current);
+        while let Some(packet) = wire1.borrow_mut().pop_front() {
+            packets += 1;
+            if current < 200 && packets != 2 {
+                continue;
+            }
+            let mut buf = ByteBuffer::with_capacity(packet.len());
+            buf.write_bytes(&packet).unwrap();
+            kcp2.input(&mut buf);
+        }
+        kcp2.update(current);
+        deliver(&wire2, &mut kcp1);
+        while kcp2.peek_size() > 0 {
+            let mut buf = ByteBuffer::with_capacity(kcp2.peek_size() as usize);
+            let n = kcp2.recv(&mut buf);
+            messages.push(buf.to_bytes()[..n as usize].to_vec());
+        }
+    }
+    assert_eq!(messages, vec![vec![0; 10]]);
+    assert_eq!(kcp1.wait_snd(), 0);
+    assert_eq!(kcp1.wait_snd_bytes(), 0);
+
+    kcp1.set_stream(true);
+    let mut buf = ByteBuffer::with_capacity(10);
+    buf.write_bytes(&[1; 10]).unwrap();
+    assert_eq!(kcp1.send_with_deadline(&mut buf, 100), -3);
+}
+
+#[test]
+fn test_unreliable() {
+    let (mut kcp1, wire1) = endpoint(1);
+    let (mut kcp2, wire2) = endpoint(1);
+    kcp1.no_delay(1, 10, 2, 1);
+    let mut buf = ByteBuffer::with_capacity(2000);
+    buf.write_bytes(&[0; 2000]).unwrap();
+    assert_eq!(kcp1.send_unreliable(&mut buf), -2);
+    for i in 0..3u8 {
+        let mut buf = ByteBuffer::with_capacity(100);
+        buf.write_bytes(&[i; 100]).unwrap();
+        assert_eq!(kcp1.send_unreliable(&mut buf), 0);
+    }
+    send_bytes(&mut kcp1, 10);
+
+    // the first flush is lost: the message is resent, the datagrams not
+    kcp1.update(10);
+    assert!(!wire1.borrow().is_empty());
+    wire1.borrow_mut().clear();
+    let mut buf = ByteBuffer::with_capacity(100);
+    buf.write_bytes(&[3; 100]).unwrap();
+    assert_eq!(kcp1.send_unreliable(&mut buf), 0);
+    let mut current = 10;
+    let messages = transfer(&mut kcp1, &wire1, &mut kcp2, &wire2, &mut current, 1);
+    assert_eq!(messages, vec![vec![0; 10]]);
+
+    let mut buf = ByteBuffer::with_capacity(10);
+    assert_eq!(kcp2.recv_unreliable(&mut buf), -3);
+    let mut buf = ByteBuffer::with_capacity(100);
+    assert_eq!(kcp2.recv_unreliable(&mut buf), 100);
+    assert!(buf.to_bytes()[..100] == [3; 100][..]);
+    let mut buf = ByteBuffer::with_capacity(100);
+    assert_eq!(kcp2.recv_unreliable(&mut buf), -1);
+}
+
+#[test]
+fn test_unordered() {
+    let (mut kcp1, wire1) = endpoint(1);
+    let (mut kcp2, wire2) = endpoint(1);
+    kcp1.no_delay(1, 10, 2, 1);
+    kcp1.set_unordered(true);
+    kcp2.set_unordered(true);
+    // a 2 fragment message whose first packet is lost, then two more
+    let mut buf = ByteBuffer::with_capacity(2000);
+    buf.write_bytes(&[1; 2000]).unwrap();
+    assert_eq!(kcp1.send(&mut buf), 0);
+    send_bytes(&mut kcp1, 10);
+    send_bytes(&mut kcp1, 20);
+    kcp1.update(10);
+    wire1.borrow_mut().retain(|packet| packet.len() < 1400);
+    deliver(&wire1, &mut kcp2);
+
+    let mut sizes = Vec::new();
+    while kcp2.peek_size() > 0 {
+        let mut buf = ByteBuffer::with_capacity(2000);
+        sizes.push(kcp2.recv(&mut buf));
+    }
+    assert_eq!(sizes, vec![10, 20]);
+
+    let mut current = 10;
+    let messages = transfer(&mut kcp1, &wire1, &mut kcp2, &wire2, &mut current, 1);
+    assert!(messages == vec![vec![1; 2000]]);
+
+    // everything in order afterwards, and nothing delivered twice
+    send_bytes(&mut kcp1, 30);
+    let messages = transfer(&mut kcp1, &wire1, &mut kcp2, &wire2, &mut current, 1);
+    assert_eq!(messages, vec![vec![0; 30]]);
+    assert_eq!(kcp2.peek_size(), -1);
+
+    // more fragments than the receive window, delivered in order
+    let large: Vec<u8> = (0..40 * 1376).map(|i| (i % 251) as u8).collect();
+    let mut buf = ByteBuffer::with_capacity(large.len());
+    buf.write_bytes(&large).unwrap();
+    assert_eq!(kcp1.send(&mut buf), 0);
+    send_bytes(&mut kcp1, 40);
+    let messages = transfer(&mut kcp1, &wire1, &mut kcp2, &wire2, &mut current, 2);
+    assert!(messages == vec![large, vec![0; 40]]);
+}
+
+#[test]
+fn test_unordered_mismatched_fragments() {
+    let mut kcp = KCP::new(1, |_, _| {});
+    kcp.set_unordered(true);
+    // a fragment and a head of different messages that end at the same sn
+    for &(cmd, sn, frg) in &[(81, 1, 3), (91, 3, 1)] {
+        let mut buf = ByteBuffer::with_capacity(100);
+        encode(&mut buf, cmd, sn, 0, b"x");
+        let mut packet = buf.to_bytes();
+        packet[5] = frg;
+        assert_eq!(kcp.input_slice(&packet), 0);
+    }
+    assert_eq!(kcp.peek_size(), -1);
+    // the head's real second fragment completes its message
+    let mut buf = ByteBuffer::with_capacity(100);
+    encode(&mut buf, 81, 4, 0, b"y");
+    assert_eq!(kcp.input(&mut buf), 0);
+    assert_eq!(recv_message(&mut kcp), Some(b"xy".to_vec()));
+}
+
+/// what a restarted process would read back
+#[cfg(feature = "serde")]
+fn reload(snapshot: KcpSnapshot) -> KcpSnapshot {
+    let bytes = bincode::serialize(&snapshot).unwrap();
+    bincode::deserialize(&bytes).unwrap()
+}
+
+#[cfg(not(feature = "serde"))]
+fn reload(snapshot: KcpSnapshot) -> KcpSnapshot {
+    snapshot
+}
+
+/// swap kcp for one restored from its snapshot, the packets still on the
+/// old wire move over to the new one
+fn restart(kcp: &mut KCP, wire: &mut Wire) {
+    let snapshot = reload(kcp.snapshot());
+    let (_, new_wire) = endpoint(0);
+    let out = new_wire.clone();
+    let restored = KCP::restore(snapshot.clone(), move |buf: &mut ByteBuffer, size: usize| {
+        out.borrow_mut().push_back(buf.to_bytes()[..size].to_vec());
+    });
+    *kcp = restored.unwrap();
+    assert!(kcp.snapshot() == snapshot);
+    new_wire.borrow_mut().extend(wire.borrow_mut().drain(..));
+    *wire = new_wire;
+}
+
+#[test]
+fn test_snapshot() {
+    let (mut kcp1, mut wire1) = endpoint(1);
+    let (mut kcp2, mut wire2) = endpoint(1);
+    kcp1.no_delay(1, 10, 2, 1);
+    kcp2.no_delay(1, 10, 2, 1);
+    let sent: Vec<Vec<u8>> = (0..40).map(|i| vec![i as u8; 1000 + i * 100]).collect();
+    for message in &sent {
+        let mut buf = ByteBuffer::with_capacity(message.len());
+        buf.write_bytes(message).unwrap();
+        assert_eq!(kcp1.send(&mut buf), 0);
+    }
+    let mut current = 0;
+    let mut received = transfer(&mut kcp1, &wire1, &mut kcp2, &wire2, &mut current, 10);
+    // a packet lost while both sides are restarted
+    current += 10;
+    kcp1.update(current);
+    wire1.borrow_mut().pop_front();
+    assert!(kcp1.wait_snd() > 0);
+
+    restart(&mut kcp1, &mut wire1);
+    restart(&mut kcp2, &mut wire2);
+    let count = sent.len() - received.len();
+    received.extend(transfer(&mut kcp1, &wire1, &mut kcp2, &wire2, &mut current, count));
+    assert!(received == sent);
+
+    let mut snapshot = kcp1.snapshot();
+    assert_eq!(snapshot.conv(), 1);
+    assert_eq!(snapshot.version(), kcp::SNAPSHOT_VERSION);
+    snapshot = reload(snapshot);
+    assert!(KCP::restore(snapshot, |_: &mut ByteBuffer, _: usize| {}).is_some());
+}
+
+#[test]
+fn test_packet_sizes() {
+    let (mut kcp, wire) = endpoint(1);
+    kcp.no_delay(1, 10, 0, 1);
+    kcp.wnd_size(128, 128);
+    send_bytes(&mut kcp, 1376 * 10 + 100);
+    kcp.update(10);
+    // full segments fill packets exactly, for UDP GSO
+    let sizes: Vec<usize> = wire.borrow().iter().map(|packet| packet.len()).collect();
+    assert_eq!(sizes, vec![1400, 1400, 1400, 1400, 1400, 1400, 1400, 1400, 1400, 1400, 124]);
+}
+
+#[test]
+fn test_large_window() {
+    let (mut kcp1, wire1) = endpoint(1);
+    let (mut kcp2, wire2) = endpoint(1);
+    kcp1.no_delay(1, 10, 2, 1);
+    kcp2.no_delay(1, 10, 2, 1);
+    kcp1.wnd_size(4096, 4096);
+    kcp2.wnd_size(4096, 4096);
+    let sent: Vec<Vec<u8>> = (0..6000).map(|i: u32| i.to_le_bytes().to_vec()).collect();
+    for message in &sent {
+        let mut buf = ByteBuffer::with_capacity(message.len());
+        buf.write_bytes(message).unwrap();
+        assert_eq!(kcp1.send(&mut buf), 0);
+    }
+    let mut received = Vec::new();
+    let mut current = 0;
+    while received.len() < sent.len() && current < 100000 {
+        current += 10;
+        kcp1.update(current);
+        // reorder the packets and lose every 7th
+        let packets: Vec<Vec<u8>> = wire1.borrow_mut().drain(..).rev().collect();
+        for (i, packet) in packets.iter().enumerate() {
+            if i % 7 != 3 {
+                wire1.borrow_mut().push_back(packet.clone());
+            }
+        }
+        deliver(&wire1, &mut kcp2);
+        kcp2.update(current);
+        deliver(&wire2, &mut kcp1);
+        while kcp2.peek_size() > 0 {
+            let mut buf = ByteBuffer::with_capacity(kcp2.peek_size() as usize);
+            let size = kcp2.recv(&mut buf) as usize;
+            received.push(buf.to_bytes()[..size].to_vec());
+        }
+    }
+    assert!(received == sent);
+    assert_eq!(kcp1.wait_snd(), 0);
+}
+
+#[test]
+fn test_recv_bytes() {
+    let (mut kcp1, wire1) = endpoint(1);
+    let (mut kcp2, wire2) = endpoint(1);
+    kcp1.no_delay(1, 10, 2, 1);
+    let large: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+    assert_eq!(kcp1.send_bytes(Bytes::new()), -1);
+    assert_eq!(kcp1.send_bytes(Bytes::from(large.clone())), 0);
+    assert_eq!(kcp1.send_bytes(Bytes::from_static(b"small")), 0);
+    assert_eq!(kcp2.recv_bytes(), None);
+
+    let mut current = 0;
+    while kcp2.peek_size() < 0 || kcp1.wait_snd() > 0 {
+        current += 10;
+        kcp1.update(current);
+        deliver(&wire1, &mut kcp2);
+        kcp2.update(current);
+        deliver(&wire2, &mut kcp1);
+    }
+    assert_eq!(kcp2.peek_bytes(), Some(Bytes::from(large.clone())));
+    // the fragments are copied together once, the single segment not at all
+    let payloads = kcp2.alloc_stats().payloads;
+    assert_eq!(kcp2.recv_bytes(), Some(Bytes::from(large)));
+    assert_eq!(kcp2.recv_bytes(), Some(Bytes::from_static(b"small")));
+    assert_eq!(kcp2.alloc_stats().payloads, payloads + 1);
+    assert_eq!(kcp2.recv_bytes(), None);
+}
+
+/// send messages of 1000 and 3000 bytes from kcp1 to kcp2 for `rounds`
+/// rounds, each received before the next is sent
+fn pool_rounds(kcp1: &mut KCP, wire1: &Wire, kcp2: &mut KCP, wire2: &Wire,
+               current: &mut u32, rounds: usize) {
+    for _ in 0..rounds {
+        for &size in &[1000, 1000, 3000, 1000] {
+            send_bytes(kcp1, size);
+        }
+        assert_eq!(transfer(kcp1, wire1, kcp2, wire2, current, 4).len(), 4);
+        while kcp1.wait_snd() > 0 {
+            *current += 10;
+            kcp1.update(*current);
+            deliver(wire1, kcp2);
+            kcp2.update(*current);
+            deliver(wire2, kcp1);
+        }
+    }
+}
+
+#[test]
+fn test_buffer_pool() {
+    let (mut kcp1, wire1) = endpoint(1);
+    let (mut kcp2, wire2) = endpoint(1);
+    kcp1.no_delay(1, 10, 2, 1);
+    // a smaller MTU keeps the output buffer
+    assert_eq!(kcp1.set_mtu(1200), 0);
+    assert_eq!(kcp1.alloc_stats().buffers, 1);
+    let mut current = 0;
+    pool_rounds(&mut kcp1, &wire1, &mut kcp2, &wire2, &mut current, 5);
+    // without a pool every message and segment has a buffer of its own
+    assert_eq!(kcp1.alloc_stats().payloads, 20);
+    assert!(kcp2.alloc_stats().payloads >= 30);
+
+    let pool = BufferPool::new(64);
+    kcp1.set_buffer_pool(Some(pool.clone()));
+    kcp2.set_buffer_pool(Some(pool.clone()));
+    pool_rounds(&mut kcp1, &wire1, &mut kcp2, &wire2, &mut current, 5);
+    let (stats1, stats2) = (kcp1.alloc_stats(), kcp2.alloc_stats());
+    assert!(!pool.is_empty());
+    pool_rounds(&mut kcp1, &wire1, &mut kcp2, &wire2, &mut current, 50);
+    // in steady state every payload buffer comes from the pool
+    assert_eq!(kcp1.alloc_stats().payloads, stats1.payloads);
+    assert_eq!(kcp2.alloc_stats().payloads, stats2.payloads);
+    assert!(kcp1.alloc_stats().reused >= stats1.reused + 50 * 6);
+    assert!(kcp2.alloc_stats().reused >= stats2.reused + 50 * 6);
+    assert_eq!(kcp1.alloc_stats().buffers, 1);
+    // nor do the segment containers grow again
+    assert_eq!(kcp1.alloc_stats().segments, stats1.segments);
+    assert_eq!(kcp2.alloc_stats().segments, stats2.segments);
+    // send and input copy out of their ByteBuffer, input_slice does not
+    assert!(kcp1.alloc_stats().reads >= stats1.reads + 50 * 4);
+    assert!(kcp2.alloc_stats().reads > stats2.reads);
+    let stats2 = kcp2.alloc_stats();
+    send_bytes(&mut kcp1, 1000);
+    current += 10;
+    kcp1.update(current);
+    while let Some(packet) = wire1.borrow_mut().pop_front() {
+        assert!(kcp2.input_slice(&packet) >= 0);
+    }
+    assert_eq!(kcp2.peek_size(), 1000);
+    assert_eq!(kcp2.alloc_stats().reads, stats2.reads);
+    assert_eq!(kcp2.alloc_stats().payloads, stats2.payloads);
+}
@@ -0,0 +1,131 @@
+use std::time::{Duration, Instant};
+use fixbuf::ByteBuffer;
+use mio::{Events, Poll, Token};
+use mio::net::UdpSocket;
+use kcp::reactor::{KcpReactor, SessionEvent, SessionId};
+
+fn reactor(poll: &Poll, token: Token) -> KcpReactor {
+    let socket = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let mut reactor = KcpReactor::new(socket);
+    reactor.register(poll.registry(), token).unwrap();
+    reactor.set_session_setup(|kcp| {
+        kcp.no_delay(1, 10, 2, 1);
+    });
+    reactor
+}
+
+fn recv_all(reactor: &mut KcpReactor, id: SessionId) -> Vec<Vec<u8>> {
+    let mut messages = Vec::new();
+    let mut buf = ByteBuffer::with_capacity(4096);
+    loop {
+        buf.clear();
+        let n = reactor.recv(id, &mut buf);
+        if n < 0 {
+            return messages;
+        }
+        messages.push(buf.to_bytes()[..n as usize].to_vec());
+    }
+}
+
+#[test]
+fn test_reactor_echo() {
+    let start = Instant::now();
+    let clock = || start.elapsed().as_millis() as u32;
+    let (mut poll, mut events) = (Poll::new().unwrap(), Events::with_capacity(64));
+    let mut server = reactor(&poll, Token(0));
+    server.set_accept(true);
+    let mut client = reactor(&poll, Token(1));
+    let server_addr = server.local_addr().unwrap();
+
+    // many sessions from one client socket, each echoed by the server
+    let ids: Vec<SessionId> = (0..50).map(|conv| client.connect(server_addr, conv)).collect();
+    for &id in &ids {
+        assert_eq!(client.send(id, &[id.0 as u8; 3000]), 0);
+    }
+    let mut accepted = 0;
+    let mut echoed = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while echoed.len() < ids.len() && Instant::now() < deadline {
+        let timeout = [server.timeout(clock()), client.timeout(clock())]
+            .iter()
+            .filter_map(|&timeout| timeout)
+            .min();
+        poll.poll(&mut events, timeout).unwrap();
+        let mut happened = Vec::new();
+        server.poll(clock(), &mut happened).unwrap();
+        for event in happened.drain(..) {
+            match event {
+                SessionEvent::Accepted(_) => accepted += 1,
+                SessionEvent::Readable(id) => {
+                    for message in recv_all(&mut server, id) {
+                        server.send(id, &message);
+                    }
+                }
+                _ => {}
+            }
+        }
+        client.poll(clock(), &mut happened).unwrap();
+        for event in happened.drain(..) {
+            if let SessionEvent::Readable(id) = event {
+                for message in recv_all(&mut client, id) {
+                    assert!(message == vec![id.0 as u8; 3000]);
+                    echoed.push(id);
+                }
+            }
+        }
+    }
+    assert_eq!(accepted, ids.len());
+    assert_eq!(echoed.len(), ids.len());
+    assert_eq!(server.len(), ids.len());
+
+    // once the last acks went out the sessions are parked, and reading
+    // from one that has nothing waiting leaves it parked
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while client.timeout(clock()).is_some() && Instant::now() < deadline {
+        poll.poll(&mut events, client.timeout(clock())).unwrap();
+        client.poll(clock(), &mut Vec::new()).unwrap();
+    }
+    assert_eq!(client.timeout(clock()), None);
+    assert!(recv_all(&mut client, ids[0]).is_empty());
+    assert_eq!(client.timeout(clock()), None);
+    assert_eq!(client.send(ids[0], &[1]), 0);
+    assert_eq!(client.timeout(clock()), Some(Duration::from_millis(0)));
+    client.close(ids[0]);
+    assert!(client.session(ids[0]).is_none());
+    assert_eq!(client.send(ids[0], &[1]), -1);
+    assert_eq!(client.len(), ids.len() - 1);
+}
+
+#[test]
+fn test_reactor_accept() {
+    let start = Instant::now();
+    let clock = || start.elapsed().as_millis() as u32;
+    let (mut poll, mut events) = (Poll::new().unwrap(), Events::with_capacity(64));
+    let mut server = reactor(&poll, Token(0));
+    server.set_accept(true);
+    server.set_max_sessions(2);
+    let mut client = reactor(&poll, Token(1));
+    let server_addr = server.local_addr().unwrap();
+
+    // datagrams that are no first data packet open no session
+    let junk = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    junk.send_to(&[1, 2, 3, 4], server_addr).unwrap();
+    junk.send_to(&[1; 64], server_addr).unwrap();
+    for conv in 0..3 {
+        let id = client.connect(server_addr, conv);
+        client.send(id, b"hello");
+    }
+    let mut accepted = 0;
+    let deadline = Instant::now() + Duration::from_millis(500);
+    while Instant::now() < deadline {
+        poll.poll(&mut events, Some(Duration::from_millis(10))).unwrap();
+        let mut happened = Vec::new();
+        server.poll(clock(), &mut happened).unwrap();
+        accepted += happened.iter()
+            .filter(|event| matches!(**event, SessionEvent::Accepted(_)))
+            .count();
+        client.poll(clock(), &mut happened).unwrap();
+    }
+    assert_eq!(accepted, 2);
+    assert_eq!(server.len(), 2);
+}
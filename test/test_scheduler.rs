@@ -0,0 +1,110 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use fixbuf::ByteBuffer;
+use kcp::{KCP, UpdateScheduler};
+
+fn due(scheduler: &mut UpdateScheduler, current: u32) -> Vec<usize> {
+    let mut due = Vec::new();
+    scheduler.advance(current, &mut due);
+    due.sort();
+    due
+}
+
+#[test]
+fn test_scheduler() {
+    let start = u32::MAX - 500;
+    let mut scheduler = UpdateScheduler::new();
+    assert_eq!(due(&mut scheduler, start), vec![]);
+    scheduler.schedule(0, start.wrapping_add(10));
+    scheduler.schedule(1, start.wrapping_add(20));
+    scheduler.schedule(2, start.wrapping_add(30));
+    scheduler.schedule_now(3);
+    assert_eq!(scheduler.len(), 4);
+    assert_eq!(scheduler.timeout(start), Some(0));
+    assert_eq!(due(&mut scheduler, start), vec![3]);
+    assert_eq!(scheduler.timeout(start), Some(10));
+
+    // rescheduling replaces the deadline, cancel forgets it
+    scheduler.schedule(0, start.wrapping_add(25));
+    scheduler.cancel(2);
+    assert!(!scheduler.is_scheduled(2));
+    assert_eq!(scheduler.timeout(start), Some(20));
+    assert_eq!(due(&mut scheduler, start.wrapping_add(15)), vec![]);
+    assert_eq!(due(&mut scheduler, start.wrapping_add(25)), vec![0, 1]);
+    assert!(scheduler.is_empty());
+    assert_eq!(scheduler.timeout(start.wrapping_add(25)), None);
+
+    // a deadline already passed is due on the next tick
+    scheduler.schedule(4, start);
+    assert_eq!(due(&mut scheduler, start.wrapping_add(26)), vec![4]);
+
+    // the clock wraps, and skipping more than a turn of the wheel loses
+    // nothing
+    scheduler.schedule(5, start.wrapping_add(1000));
+    scheduler.schedule(6, start.wrapping_add(3000));
+    assert_eq!(due(&mut scheduler, start.wrapping_add(2000)), vec![5]);
+    assert_eq!(due(&mut scheduler, start.wrapping_add(2999)), vec![]);
+    assert_eq!(due(&mut scheduler, start.wrapping_add(5000)), vec![6]);
+    assert!(scheduler.is_empty());
+}
+
+type Wire = Rc<RefCell<VecDeque<Vec<u8>>>>;
+
+fn endpoint(conv: u32) -> (KCP, Wire) {
+    let wire: Wire = Rc::new(RefCell::new(VecDeque::new()));
+    let out = wire.clone();
+    let mut kcp = KCP::new(conv, move |buf: &mut ByteBuffer, size: usize| {
+        out.borrow_mut().push_back(buf.to_bytes()[..size].to_vec());
+    });
+    kcp.no_delay(1, 10, 2, 1);
+    (kcp, wire)
+}
+
+#[test]
+fn test_scheduler_sessions() {
+    let mut scheduler = UpdateScheduler::new();
+    let (mut kcps, wires): (Vec<KCP>, Vec<Wire>) = (0..2).map(|_| endpoint(1)).unzip();
+    let mut buf = ByteBuffer::with_capacity(64 * 1024);
+    buf.write_bytes(&vec![7u8; 64 * 1024]).unwrap();
+    kcps[0].send(&mut buf);
+    scheduler.schedule_now(0);
+    scheduler.schedule_now(1);
+
+    let mut received = 0;
+    let mut updates = 0;
+    let mut current = 0;
+    let mut slots = Vec::new();
+    while current < 10000 {
+        slots.clear();
+        scheduler.advance(current, &mut slots);
+        for &slot in &slots {
+            kcps[slot].update(current);
+            updates += 1;
+            if !kcps[slot].is_idle() {
+                let deadline = kcps[slot].check(current);
+                scheduler.schedule(slot, deadline);
+            }
+        }
+        for slot in 0..2 {
+            while let Some(packet) = wires[slot].borrow_mut().pop_front() {
+                let mut data = ByteBuffer::with_capacity(packet.len());
+                data.write_bytes(&packet).unwrap();
+                kcps[1 - slot].input(&mut data);
+                scheduler.schedule_now(1 - slot);
+            }
+        }
+        while kcps[1].peek_size() > 0 {
+            let mut data = ByteBuffer::with_capacity(kcps[1].peek_size() as usize);
+            received += kcps[1].recv(&mut data);
+            scheduler.schedule_now(1);
+        }
+        current += 1;
+    }
+    assert_eq!(received, 64 * 1024);
+    // once everything is acked both sessions are parked
+    assert!(scheduler.is_empty());
+    assert!(kcps[0].is_idle() && kcps[1].is_idle());
+    // far fewer updates than one per session and interval
+    assert!(updates < 2 * 10000 / 10, "{} updates", updates);
+}
@@ -0,0 +1,241 @@
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+use fixbuf::ByteBuffer;
+use kcp::KCP;
+use kcp::smux::{Config, Session};
+
+/// move all pending frames from one session to the other
+fn exchange(a: &Session, b: &Session) {
+    loop {
+        let ab = a.output(usize::MAX);
+        let ba = b.output(usize::MAX);
+        if ab.is_empty() && ba.is_empty() {
+            break;
+        }
+        b.input(&ab).unwrap();
+        a.input(&ba).unwrap();
+    }
+}
+
+#[test]
+fn test_frame_format() {
+    let session = Session::client(Default::default()).unwrap();
+    let mut stream = session.open_stream().unwrap();
+    assert_eq!(stream.id(), 3);
+    stream.write_all(b"hi").unwrap();
+    stream.close();
+    assert_eq!(session.output(usize::MAX),
+               vec![1, 0, 0, 0, 3, 0, 0, 0,
+                    1, 2, 2, 0, 3, 0, 0, 0, b'h', b'i',
+                    1, 1, 0, 0, 3, 0, 0, 0]);
+    assert_eq!(Session::server(Default::default()).unwrap().open_stream().unwrap().id(), 2);
+
+    let config = Config { version: 3, ..Default::default() };
+    assert!(Session::client(config).is_err());
+    assert!(session.input(&[2, 3, 0, 0, 0, 0, 0, 0]).is_err());
+    assert!(session.is_closed());
+}
+
+#[test]
+fn test_open_accept_close() {
+    let client = Session::client(Default::default()).unwrap();
+    let server = Session::server(Default::default()).unwrap();
+    let mut a = client.open_stream().unwrap();
+    a.write_all(b"ping").unwrap();
+    exchange(&client, &server);
+
+    let mut b = server.try_accept_stream().unwrap();
+    assert_eq!(b.id(), a.id());
+    let mut buf = [0; 16];
+    assert_eq!(b.read(&mut buf).unwrap(), 4);
+    assert_eq!(&buf[..4], b"ping");
+    b.set_nonblocking(true);
+    assert_eq!(b.read(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+    a.close();
+    exchange(&client, &server);
+    assert_eq!(b.read(&mut buf).unwrap(), 0);
+    drop(b);
+    exchange(&client, &server);
+    assert_eq!(client.num_streams(), 0);
+    assert_eq!(server.num_streams(), 0);
+}
+
+#[test]
+fn test_stream_window() {
+    let config = Config { version: 2, max_stream_buffer: 1024, ..Default::default() };
+    let client = Session::client(config.clone()).unwrap();
+    let server = Session::server(config).unwrap();
+    let mut a = client.open_stream().unwrap();
+    a.set_nonblocking(true);
+    // until the first UPD the peer's window is smux's initialPeerWindow
+    let data = vec![7u8; 300000];
+    let mut written = 0;
+    loop {
+        match a.write(&data) {
+            Ok(n) => written += n,
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+                break;
+            }
+        }
+        exchange(&client, &server);
+    }
+    assert_eq!(written, 262144);
+    exchange(&client, &server);
+
+    let mut b = server.try_accept_stream().unwrap();
+    let mut buf = vec![0; 300000];
+    let mut read = 0;
+    while read < 262144 {
+        read += b.read(&mut buf).unwrap();
+    }
+    assert_eq!(read, 262144);
+    exchange(&client, &server);
+    assert_eq!(a.write(&data).unwrap(), 1024);
+    assert_eq!(a.write(&data).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+}
+
+#[test]
+fn test_keepalive() {
+    let config = Config {
+        keepalive_interval: 100,
+        keepalive_timeout: 300,
+        ..Default::default()
+    };
+    let session = Session::client(config).unwrap();
+    assert!(session.update(0));
+    assert!(session.update(100));
+    assert_eq!(session.output(usize::MAX), vec![1, 3, 0, 0, 0, 0, 0, 0]);
+    session.input(&[1, 3, 0, 0, 0, 0, 0, 0]).unwrap();
+    assert!(session.update(300));
+    assert!(!session.update(600));
+    assert!(session.open_stream().is_err());
+}
+
+/// datagrams sent by one end, waiting for the other
+type Wire = Rc<RefCell<Vec<Vec<u8>>>>;
+
+fn kcp_pair() -> (KCP, KCP, Wire, Wire) {
+    let to2 = Rc::new(RefCell::new(Vec::new()));
+    let to1 = Rc::new(RefCell::new(Vec::new()));
+    let out1 = to2.clone();
+    let out2 = to1.clone();
+    let mut kcp1 = KCP::new(1, move |buf: &mut ByteBuffer, size: usize| {
+        out1.borrow_mut().push(buf.to_bytes()[..size].to_vec());
+    });
+    let mut kcp2 = KCP::new(1, move |buf: &mut ByteBuffer, size: usize| {
+        out2.borrow_mut().push(buf.to_bytes()[..size].to_vec());
+    });
+    for kcp in &mut [&mut kcp1, &mut kcp2] {
+        kcp.set_stream(true);
+        kcp.wnd_size(128, 128);
+        kcp.no_delay(1, 10, 2, 1);
+    }
+    (kcp1, kcp2, to1, to2)
+}
+
+fn deliver(packets: &Rc<RefCell<Vec<Vec<u8>>>>, kcp: &mut KCP) {
+    for packet in packets.borrow_mut().drain(..) {
+        let mut buf = ByteBuffer::with_capacity(packet.len());
+        buf.write_bytes(&packet).unwrap();
+        kcp.input(&mut buf);
+    }
+}
+
+#[test]
+fn test_streams_over_kcp() {
+    let (mut kcp1, mut kcp2, to1, to2) = kcp_pair();
+    let client = Session::client(Default::default()).unwrap();
+    let server = Session::server(Default::default()).unwrap();
+
+    let mut handles = Vec::new();
+    for i in 0..4u8 {
+        let mut stream = client.open_stream().unwrap();
+        handles.push(thread::spawn(move || {
+            let data = vec![i; 100000];
+            stream.write_all(&data).unwrap();
+            let mut echo = vec![0; data.len()];
+            stream.read_exact(&mut echo).unwrap();
+            assert!(echo == data);
+        }));
+    }
+    let acceptor = server.clone();
+    thread::spawn(move || {
+        while let Ok(mut stream) = acceptor.accept_stream() {
+            thread::spawn(move || {
+                let mut buf = [0; 4096];
+                loop {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => stream.write_all(&buf[..n]).unwrap(),
+                    }
+                }
+            });
+        }
+    });
+
+    // every stream is forgotten once both ends closed it
+    let mut current = 0;
+    while client.num_streams() > 0 && current < 600000 {
+        current += 10;
+        client.pump(&mut kcp1).unwrap();
+        server.pump(&mut kcp2).unwrap();
+        kcp1.update(current);
+        kcp2.update(current);
+        deliver(&to2, &mut kcp2);
+        deliver(&to1, &mut kcp1);
+        assert!(client.update(current) && server.update(current));
+        thread::sleep(Duration::from_millis(1));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(client.num_streams(), 0);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async_read_wakes() {
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::{Context, Poll, Wake};
+    use futures_io::AsyncRead;
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let client = Session::client(Default::default()).unwrap();
+    let server = Session::server(Default::default()).unwrap();
+    let mut a = client.open_stream().unwrap();
+    exchange(&client, &server);
+    let mut b = server.try_accept_stream().unwrap();
+
+    // only the task that polled last is woken
+    let stale = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = stale.clone().into();
+    let mut buf = [0; 16];
+    assert!(Pin::new(&mut b).poll_read(&mut Context::from_waker(&waker), &mut buf).is_pending());
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = flag.clone().into();
+    let mut cx = Context::from_waker(&waker);
+    assert!(Pin::new(&mut b).poll_read(&mut cx, &mut buf).is_pending());
+    assert!(Pin::new(&mut b).poll_read(&mut cx, &mut buf).is_pending());
+    a.write_all(b"wake").unwrap();
+    exchange(&client, &server);
+    assert!(flag.0.load(Ordering::SeqCst));
+    assert!(!stale.0.load(Ordering::SeqCst));
+    match Pin::new(&mut b).poll_read(&mut cx, &mut buf) {
+        Poll::Ready(Ok(n)) => assert_eq!(&buf[..n], b"wake"),
+        _ => panic!("expected data"),
+    }
+}
@@ -0,0 +1,122 @@
+use std::io::{self, Cursor, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::thread;
+use std::time::Duration;
+use kcp::socks5;
+use kcp::tunnel::{Client, Config, Server};
+use util::echo_server;
+
+#[test]
+fn test_addr_encoding() {
+    for &(host, port, expect) in &[("127.0.0.1", 80, "127.0.0.1:80"),
+                                   ("::1", 443, "[::1]:443"),
+                                   ("example.com", 8080, "example.com:8080")] {
+        let addr = socks5::encode_addr(host, port).unwrap();
+        assert_eq!(socks5::decode_addr(&addr[..addr.len() - 1]).unwrap(), None);
+        let (decoded, n) = socks5::decode_addr(&addr).unwrap().unwrap();
+        assert_eq!(decoded, expect);
+        assert_eq!(n, addr.len());
+    }
+    assert!(socks5::decode_addr(&[9, 0, 0]).is_err());
+    let long = "a".repeat(256);
+    assert!(socks5::encode_addr(&long, 80).is_err());
+    assert_eq!(socks5::encode_addr(&long[1..], 80).unwrap().len(), 1 + 1 + 255 + 2);
+}
+
+struct Conn {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_handshake() {
+    let mut conn = Conn {
+        input: Cursor::new(vec![5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0, 80]),
+        output: Vec::new(),
+    };
+    assert_eq!(socks5::accept(&mut conn).unwrap(), socks5::encode_addr("127.0.0.1", 80).unwrap());
+    // the request is answered only after connecting
+    assert_eq!(conn.output, &[5, 0]);
+    socks5::reply(&mut conn, socks5::REP_SUCCEEDED).unwrap();
+    assert_eq!(&conn.output[..4], &[5, 0, 5, 0]);
+
+    // BIND is refused with "command not supported"
+    let mut conn = Conn {
+        input: Cursor::new(vec![5, 1, 0, 5, 2, 0, 1, 127, 0, 0, 1, 0, 80]),
+        output: Vec::new(),
+    };
+    assert!(socks5::accept(&mut conn).is_err());
+    assert_eq!(&conn.output[..4], &[5, 0, 5, 7]);
+}
+
+#[test]
+fn test_reply_code() {
+    for &(kind, rep) in &[(io::ErrorKind::ConnectionRefused, 5),
+                          (io::ErrorKind::HostUnreachable, 4),
+                          (io::ErrorKind::NetworkUnreachable, 3),
+                          (io::ErrorKind::Other, 1)] {
+        assert_eq!(socks5::reply_code(&io::Error::new(kind, "connect")), rep);
+    }
+}
+
+/// ask `proxy` to connect to `host:port` and return the reply code
+fn request(proxy: SocketAddr, host: &str, port: u16) -> (TcpStream, u8) {
+    let mut conn = TcpStream::connect(proxy).unwrap();
+    conn.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    conn.write_all(&[5, 1, 0]).unwrap();
+    let mut method = [0; 2];
+    conn.read_exact(&mut method).unwrap();
+    assert_eq!(method, [5, 0]);
+    let mut req = vec![5, 1, 0];
+    req.extend(socks5::encode_addr(host, port).unwrap());
+    conn.write_all(&req).unwrap();
+    let mut rep = [0; 10];
+    conn.read_exact(&mut rep).unwrap();
+    assert_eq!(rep[0], 5);
+    (conn, rep[1])
+}
+
+#[test]
+fn test_socks5_proxy() {
+    let target = echo_server().local_addr().unwrap();
+    let config: Config = Default::default();
+    let server = Server::socks5(UdpSocket::bind("127.0.0.1:0").unwrap(), config.clone());
+    let server_addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    let mut client = Client::new(TcpListener::bind("127.0.0.1:0").unwrap(), server_addr, config);
+    client.set_socks5(true);
+    let proxy = client.local_addr().unwrap();
+    thread::spawn(move || client.run());
+
+    for host in &["127.0.0.1", "localhost"] {
+        let (mut conn, rep) = request(proxy, host, target.port());
+        assert_eq!(rep, socks5::REP_SUCCEEDED);
+
+        conn.write_all(b"hello through socks5").unwrap();
+        let mut echo = [0; 20];
+        conn.read_exact(&mut echo).unwrap();
+        assert_eq!(&echo, b"hello through socks5");
+    }
+
+    // a refused connect is reported to the SOCKS5 client
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let (mut conn, rep) = request(proxy, "127.0.0.1", closed.port());
+    assert_eq!(rep, 5);
+    assert_eq!(conn.read(&mut [0; 16]).unwrap(), 0);
+}
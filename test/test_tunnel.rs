@@ -0,0 +1,194 @@
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use kcp::tunnel::{Client, Config, Options, Server};
+use util::echo_server;
+
+#[test]
+fn test_options() {
+    let args = vec!["-l", ":12948", "-r", "1.2.3.4:29900", "--mtu", "1200", "--mtu-max",
+                    "1472", "--sndwnd", "256"];
+    let opts = Options::parse(args.into_iter().map(String::from)).unwrap();
+    assert_eq!(opts.remote, "1.2.3.4:29900");
+    assert_eq!(opts.config.mtu, 1200);
+    assert_eq!(opts.config.mtu_max, 1472);
+    assert_eq!(opts.config.sndwnd, 256);
+    assert!(!opts.config.migrate);
+    let args = vec!["-l", ":29900", "-t", "127.0.0.1:22", "--validate-path"];
+    let opts = Options::parse(args.into_iter().map(String::from)).unwrap();
+    assert!(opts.config.migrate && opts.config.validate_path);
+    assert!(Options::parse(vec!["-l".to_string()]).is_err());
+    assert!(Options::parse(vec!["--bogus".to_string(), "1".to_string()]).is_err());
+    let args = vec!["-l", ":29900", "-t", "127.0.0.1:22", "--timeout", "3600"];
+    assert_eq!(Options::parse(args.into_iter().map(String::from)).unwrap().config.timeout, 3600000);
+    let args = vec!["-l", ":29900", "-t", "127.0.0.1:22", "--timeout", "4294968"];
+    assert!(Options::parse(args.into_iter().map(String::from)).is_err());
+}
+
+fn loopback(config: Config) {
+    let target = echo_server().local_addr().unwrap();
+    let server = Server::new(UdpSocket::bind("127.0.0.1:0").unwrap(), target, config.clone());
+    let server_addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    let client = Client::new(TcpListener::bind("127.0.0.1:0").unwrap(), server_addr, config);
+    let client_addr = client.local_addr().unwrap();
+    thread::spawn(move || client.run());
+
+    let mut conn = TcpStream::connect(client_addr).unwrap();
+    let data: Vec<u8> = (0..200000).map(|i| (i % 251) as u8).collect();
+    let mut writer = conn.try_clone().unwrap();
+    let expected = data.clone();
+    thread::spawn(move || writer.write_all(&data).unwrap());
+    let mut received = vec![0; expected.len()];
+    conn.read_exact(&mut received).unwrap();
+    assert!(received == expected);
+}
+
+#[test]
+fn test_tunnel_loopback() {
+    let mut config: Config = Default::default();
+    loopback(config.clone());
+    config.gso = true;
+    loopback(config);
+}
+
+/// forwards the client's datagrams to `server` from a relay socket that is
+/// replaced once `moved` is set, like a client switching networks; the old
+/// socket stops relaying replies
+fn relay(server: SocketAddr, moved: Arc<AtomicBool>) -> SocketAddr {
+    let front = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let old = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let new = UdpSocket::bind("127.0.0.1:0").unwrap();
+    for socket in &[&front, &old, &new] {
+        socket.set_read_timeout(Some(Duration::from_millis(1))).unwrap();
+    }
+    let addr = front.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut client = None;
+        let mut buf = [0; 65536];
+        loop {
+            let moved = moved.load(Ordering::SeqCst);
+            let out = if moved { &new } else { &old };
+            while let Ok((n, from)) = front.recv_from(&mut buf) {
+                client = Some(from);
+                out.send_to(&buf[..n], server).unwrap();
+            }
+            while let Ok(n) = old.recv(&mut buf) {
+                if !moved {
+                    front.send_to(&buf[..n], client.unwrap()).unwrap();
+                }
+            }
+            while let Ok(n) = new.recv(&mut buf) {
+                front.send_to(&buf[..n], client.unwrap()).unwrap();
+            }
+        }
+    });
+    addr
+}
+
+fn migrate(validate_path: bool) {
+    let target = echo_server().local_addr().unwrap();
+    let config = Config { migrate: true, validate_path, ..Default::default() };
+    let server = Server::new(UdpSocket::bind("127.0.0.1:0").unwrap(), target, config.clone());
+    let moved = Arc::new(AtomicBool::new(false));
+    let relay_addr = relay(server.local_addr().unwrap(), moved.clone());
+    thread::spawn(move || server.run());
+    let client = Client::new(TcpListener::bind("127.0.0.1:0").unwrap(), relay_addr, config);
+    let client_addr = client.local_addr().unwrap();
+    thread::spawn(move || client.run());
+
+    let mut conn = TcpStream::connect(client_addr).unwrap();
+    conn.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let data: Vec<u8> = (0..50000).map(|i| (i % 251) as u8).collect();
+    let mut received = vec![0; data.len()];
+    for _ in 0..2 {
+        conn.write_all(&data).unwrap();
+        conn.read_exact(&mut received).unwrap();
+        assert!(received == data);
+        moved.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_tunnel_migration() {
+    migrate(false);
+    migrate(true);
+}
+
+#[test]
+fn test_tunnel_close() {
+    let target = echo_server().local_addr().unwrap();
+    let config: Config = Default::default();
+    let server = Server::new(UdpSocket::bind("127.0.0.1:0").unwrap(), target, config.clone());
+    let server_addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    let client = Client::new(TcpListener::bind("127.0.0.1:0").unwrap(), server_addr, config);
+    let client_addr = client.local_addr().unwrap();
+    thread::spawn(move || client.run());
+
+    // the end of file crosses the tunnel both ways, long before the timeout
+    let mut conn = TcpStream::connect(client_addr).unwrap();
+    conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    conn.write_all(b"last words").unwrap();
+    conn.shutdown(Shutdown::Write).unwrap();
+    let mut received = Vec::new();
+    conn.read_to_end(&mut received).unwrap();
+    assert_eq!(received, b"last words");
+}
+
+#[test]
+fn test_tunnel_refused() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let config: Config = Default::default();
+    let server = Server::new(UdpSocket::bind("127.0.0.1:0").unwrap(), target, config.clone());
+    let server_addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    let client = Client::new(TcpListener::bind("127.0.0.1:0").unwrap(), server_addr, config);
+    let client_addr = client.local_addr().unwrap();
+    thread::spawn(move || client.run());
+
+    // the session stays to close the tunnel instead of reconnecting to the
+    // target for every retransmit
+    let mut conn = TcpStream::connect(client_addr).unwrap();
+    conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    conn.write_all(b"anyone there").unwrap();
+    let mut received = Vec::new();
+    conn.read_to_end(&mut received).unwrap();
+    assert!(received.is_empty());
+}
+
+#[test]
+fn test_tunnel_open() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    target.set_nonblocking(true).unwrap();
+    let config = Config { max_sessions: 1, ..Default::default() };
+    let server = Server::new(UdpSocket::bind("127.0.0.1:0").unwrap(),
+                             target.local_addr().unwrap(),
+                             config.clone());
+    let server_addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    // datagrams that are no first data packet do not open sessions
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    udp.send_to(&[1, 2, 3, 4], server_addr).unwrap();
+    udp.send_to(&[1; 64], server_addr).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert!(target.accept().is_err());
+
+    // only max_sessions are served
+    let client = Client::new(TcpListener::bind("127.0.0.1:0").unwrap(), server_addr, config);
+    let client_addr = client.local_addr().unwrap();
+    thread::spawn(move || client.run());
+    let mut conns = Vec::new();
+    for _ in 0..2 {
+        let mut conn = TcpStream::connect(client_addr).unwrap();
+        conn.write_all(b"hello").unwrap();
+        conns.push(conn);
+    }
+    thread::sleep(Duration::from_millis(500));
+    assert!(target.accept().is_ok());
+    assert!(target.accept().is_err());
+}
@@ -0,0 +1,25 @@
+//! fixtures shared by the test modules
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+
+/// a TCP server on a local port echoing whatever each connection sends
+pub fn echo_server() -> TcpListener {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let accept = listener.try_clone().unwrap();
+    thread::spawn(move || {
+        for stream in accept.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut buf = [0; 4096];
+                loop {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => stream.write_all(&buf[..n]).unwrap(),
+                    }
+                }
+            });
+        }
+    });
+    listener
+}